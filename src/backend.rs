@@ -0,0 +1,64 @@
+use crate::codegen::CodeGen;
+use crate::codegen_c::Transpiler;
+use crate::codegen_wasm::WasmGen;
+use crate::interp::Interpreter;
+use crate::lang::hir::{Function, Program};
+use crate::lang::error::{LangError, lang_error_global};
+
+// What a backend hands back once every function has been defined.
+#[derive(Debug, Clone)]
+pub enum Output {
+  Object { ir: String, object: Vec<u8> }, // ir is the backend's own, e.g CLIF
+  Value(i64), // what main returned, for backends that run the program
+  Source(String), // for backends that translate to another language
+}
+
+pub trait Backend<'a> {
+  // Called for every function before any is defined, so definitions can
+  // call functions that come later in the source.
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError>;
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError>;
+  fn emit(self: Box<Self>) -> Result<Output, LangError>;
+}
+
+// Looks up a backend by name: cranelift and llvm emit an object file for
+// `target`, c emits portable C source, cranelift-jit and interp run the
+// program on the host. Cranelift can't generate wasm, so wasm32 targets get
+// a module from the wasm backend instead.
+pub fn create<'a>(name: &str, target: Option<&str>, optimize: bool) -> Result<Box<dyn Backend<'a> + 'a>, LangError> {
+  match name {
+    "cranelift" if target.is_some_and(is_wasm) => Ok(Box::new(WasmGen::new())),
+    "cranelift" => Ok(Box::new(CodeGen::with_optimization(target, optimize)?)),
+    #[cfg(feature = "llvm")]
+    "llvm" => Ok(Box::new(crate::codegen_llvm::Compiler::new(target, optimize)?)),
+    #[cfg(not(feature = "llvm"))]
+    "llvm" => Err(lang_error_global("The llvm backend isn't available, build scaffold with '--features llvm'")),
+    "cranelift-jit" | "interp" if target.is_some() => {
+      Err(lang_error_global(&format!("The {} backend can only run programs on the host", name)))
+    }
+    "c" if target.is_some() => {
+      Err(lang_error_global("The c backend emits portable C, the C compiler picks the target"))
+    }
+    "c" => Ok(Box::new(Transpiler::new())),
+    "cranelift-jit" => Ok(Box::new(CodeGen::jit()?)),
+    "interp" => Ok(Box::new(Interpreter::new())),
+    _ => Err(lang_error_global(&format!("Unknown backend '{}'", name))),
+  }
+}
+
+pub fn is_wasm(target: &str) -> bool {
+  target.split('-').next() == Some("wasm32")
+}
+
+// Feeds a lowered program through a backend, declaring then defining every
+// function, `main` last. Only `CodeGen::eval` takes programs with globals.
+pub fn drive<'a>(mut backend: Box<dyn Backend<'a> + 'a>, program: &'a Program) -> Result<Output, LangError> {
+  assert!(program.globals.is_empty(), "globals only outlive programs in the REPL");
+  for func in &program.functions {
+    backend.declare_function(func)?;
+  }
+  for func in &program.functions {
+    backend.define_function(func)?;
+  }
+  backend.emit()
+}
@@ -1,131 +1,386 @@
-use std::{collections::HashMap, convert::TryInto, fs::File, path::Path};
-
 use inkwell::{
-  context::Context, 
+  basic_block::BasicBlock,
+  builder::Builder,
+  context::Context,
+  module::{Linkage, Module},
   passes::PassManager,
-  values::{FunctionValue, PointerValue, AnyValue, AnyValueEnum, IntValue, BasicValue}, 
-  module::Module,
-  execution_engine::FunctionLookupError,
-  builder::Builder, types::{AnyTypeEnum, IntType}, targets::{TargetMachine, Target, RelocMode, CodeModel, FileType}, OptimizationLevel
+  targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetData, TargetMachine, TargetTriple},
+  types::IntType,
+  values::{FunctionValue, IntValue, PointerValue},
+  AddressSpace, IntPredicate, OptimizationLevel,
 };
-use std::str::FromStr;
 
-use crate::lang::{error::{LangError, lang_error_fatal, span, span_single}, parse::{Parser, Expr, ExprKind}, self};
+use crate::backend::{Backend, Output};
+use crate::lang::hir::{self, BinaryOp, DivOp, Expr, ExprKind, Function, Trap, Type, UnaryOp};
+use crate::lang::error::{LangError, lang_error_fatal, lang_error_global};
 
-pub struct Compiler<'a, 'ctx> {
-  pub context: &'ctx Context,
-  pub builder: &'a Builder<'ctx>,
-  pub fpm: &'a PassManager<FunctionValue<'ctx>>,
-  pub module: &'a Module<'ctx>,
+thread_local! {
+  // inkwell's modules borrow the context they're made in, and a compiler's
+  // module lives from the first declaration until `emit`, so the modules a
+  // thread builds share a context that's never freed.
+  static CONTEXT: &'static Context = Box::leak(Box::new(Context::create()));
+}
 
-  /*variables: HashMap<String, PointerValue<'ctx>>,*/
-  /*fn_value_*/
+pub struct Compiler {
+  context: &'static Context,
+  machine: TargetMachine,
+  target_data: TargetData,
+  fpm: PassManager<FunctionValue<'static>>, // dropped before the module it runs on
+  module: Module<'static>,
+  builder: Builder<'static>,
+  runtime: Runtime<'static>,
+  functions: Vec<FunctionValue<'static>>, // indexed by FuncId
 }
 
-use AnyValueEnum::*;
-impl<'a, 'ctx> Compiler<'a, 'ctx> {
-  fn compile_expr(&mut self, expr: &Expr) -> Result<AnyValueEnum<'ctx>, LangError> {
-    match &expr.kind {
-      ExprKind::Number(num) => {
-        Ok(IntValue(self.context.i64_type().const_int(num.parse().unwrap(), false)))
-      }
-      
-      _ => unimplemented!()
+// The C library functions a division's report is written and aborted with.
+#[derive(Clone, Copy)]
+struct Runtime<'ctx> {
+  write: FunctionValue<'ctx>,
+  abort: FunctionValue<'ctx>,
+}
+
+impl Compiler {
+  // Targets the host when no triple is given. With `optimize`, every function
+  // goes through LLVM's function pass manager once it's defined.
+  pub fn new(target: Option<&str>, optimize: bool) -> Result<Self, LangError> {
+    let machine = target_machine(target, optimize)?;
+    let target_data = machine.get_target_data();
+    let context = CONTEXT.with(|context| *context);
+    let module = context.create_module("main");
+    module.set_triple(&machine.get_triple());
+    module.set_data_layout(&target_data.get_data_layout());
+
+    let fpm = PassManager::create(&module);
+    if optimize {
+      fpm.add_promote_memory_to_register_pass();
+      fpm.add_instruction_combining_pass();
+      fpm.add_reassociate_pass();
+      fpm.add_gvn_pass();
+      fpm.add_cfg_simplification_pass();
+      fpm.add_basic_alias_analysis_pass();
+      fpm.add_instruction_combining_pass();
     }
+    fpm.initialize();
+
+    let size_type = context.ptr_sized_int_type(&target_data, None);
+    let i8_ptr = context.i8_type().ptr_type(AddressSpace::default());
+    let write_type = size_type.fn_type(&[context.i32_type().into(), i8_ptr.into(), size_type.into()], false);
+    let runtime = Runtime {
+      write: module.add_function("write", write_type, Some(Linkage::External)),
+      abort: module.add_function("abort", context.void_type().fn_type(&[], false), Some(Linkage::External)),
+    };
+
+    Ok(Compiler {
+      context,
+      machine,
+      target_data,
+      fpm,
+      module,
+      builder: context.create_builder(),
+      runtime,
+      functions: vec![],
+    })
   }
-  
-  fn compile_main(&mut self, stmt: &Expr) -> Result<FunctionValue<'ctx>, LangError> {
-    let main_type = self.module.add_function(
-      "main",
-      self.context.i32_type().fn_type(&[], false),
-      None
-    );
-    let entry = self.context.append_basic_block(main_type, "entry");
-    
-    self.builder.position_at_end(entry);
-    let body = self.compile_expr(stmt)?;
-    
-    self.builder.build_return(None);
-    //match body {
-      //IntValue(n) => {
-        //let casted = n.const_cast(self.context.i32_type(), true);
-        //self.builder.build_return(Some(&casted));
-      //},
-      //_ => unimplemented!()
-    //}
-
-    if main_type.verify(true) {
-      self.fpm.run_on(&main_type);
-      
-      Ok(main_type)
+}
+
+fn target_machine(target: Option<&str>, optimize: bool) -> Result<TargetMachine, LangError> {
+  Target::initialize_all(&InitializationConfig::default());
+  let triple = match target {
+    Some(t) => TargetTriple::create(t),
+    None => TargetMachine::get_default_triple(),
+  };
+  let name = triple.as_str().to_string_lossy().into_owned();
+  let target = Target::from_triple(&triple)
+    .map_err(|e| lang_error_global(&format!("Unsupported target '{}': {}", name, e.to_string_lossy())))?;
+  let opt_level = if optimize { OptimizationLevel::Aggressive } else { OptimizationLevel::None };
+  target.create_target_machine(&triple, "generic", "", opt_level, RelocMode::PIC, CodeModel::Default)
+    .ok_or_else(|| lang_error_global(&format!("Unsupported target '{}'", name)))
+}
+
+impl<'a> Backend<'a> for Compiler {
+  // main keeps its name for the C runtime to call, the others are mangled so
+  // they can't clash with the runtime's functions.
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let param_types: Vec<_> = func.params().iter().map(|p| int_type(self.context, p.ty).into()).collect();
+    let fn_type = int_type(self.context, func.ret).fn_type(&param_types, false);
+    let value = if func.is_main() {
+      self.module.add_function("main", fn_type, Some(Linkage::External))
     } else {
-      unsafe {
-        main_type.delete();
+      self.module.add_function(&hir::mangle(&func.name), fn_type, Some(Linkage::Internal))
+    };
+    self.functions.push(value);
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let value = self.functions[func.id.0];
+    let entry = self.context.append_basic_block(value, "entry");
+    self.builder.position_at_end(entry);
+
+    let mut translator = FunctionTranslator {
+      context: self.context,
+      builder: &self.builder,
+      target_data: &self.target_data,
+      runtime: self.runtime,
+      function: value,
+      functions: &self.functions,
+      locals: vec![],
+      loops: vec![],
+    };
+    for local in &func.locals {
+      let ptr = translator.alloca(local.ty);
+      translator.locals.push(ptr);
+    }
+    for (ptr, arg) in translator.locals.iter().zip(value.get_param_iter()) {
+      self.builder.build_store(*ptr, arg);
+    }
+
+    let r = translator.translate_block(&func.body);
+    self.builder.build_return(Some(&r));
+
+    if !value.verify(false) {
+      return Err(lang_error_fatal("Code generation produced an invalid function", func.span));
+    }
+    self.fpm.run_on(&value);
+    Ok(())
+  }
+
+  fn emit(self: Box<Self>) -> Result<Output, LangError> {
+    self.module.verify()
+      .map_err(|e| lang_error_global(&format!("Code generation failed: {}", e.to_string_lossy())))?;
+    let object = self.machine.write_to_memory_buffer(&self.module, FileType::Object)
+      .map_err(|e| lang_error_global(&format!("Failed to emit object file: {}", e.to_string_lossy())))?;
+    Ok(Output::Object { ir: self.module.print_to_string().to_string(), object: object.as_slice().to_vec() })
+  }
+}
+
+struct FunctionTranslator<'a, 'ctx> {
+  context: &'ctx Context,
+  builder: &'a Builder<'ctx>,
+  target_data: &'a TargetData,
+  runtime: Runtime<'ctx>,
+  function: FunctionValue<'ctx>,
+  functions: &'a [FunctionValue<'ctx>], // indexed by FuncId
+  locals: Vec<PointerValue<'ctx>>, // indexed by LocalId
+  loops: Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>, // (header, exit) of each enclosing loop
+}
+
+impl<'a, 'ctx> FunctionTranslator<'a, 'ctx> {
+  fn translate_expr(&mut self, expr: &Expr) -> IntValue<'ctx> {
+    let ty = expr.ty;
+    let i64_type = self.context.i64_type();
+    let i8_type = self.context.i8_type();
+    match &expr.kind {
+      ExprKind::Int(n) => int_type(self.context, ty).const_int(*n as u64, false),
+      ExprKind::Bool(b) => i8_type.const_int(*b as u64, false),
+      ExprKind::Local(id) => self.builder.build_load(self.locals[id.0], "").into_int_value(),
+      ExprKind::Unary(op, rhs) => {
+        let val = self.translate_expr(rhs);
+        match op {
+          UnaryOp::Not => {
+            let cmp = self.builder.build_int_compare(IntPredicate::EQ, val, val.get_type().const_zero(), "");
+            self.builder.build_int_z_extend(cmp, i8_type, "")
+          }
+          UnaryOp::Neg => self.builder.build_int_neg(val, ""),
+          UnaryOp::BitNot => self.builder.build_not(val, ""),
+        }
+      },
+      ExprKind::Binary(lhs, op, rhs) => {
+        let unsigned = lhs.ty.is_unsigned();
+        let llhs = self.translate_expr(lhs);
+        let lrhs = self.translate_expr(rhs);
+        match op {
+          BinaryOp::Add => self.builder.build_int_add(llhs, lrhs, ""),
+          BinaryOp::Sub => self.builder.build_int_sub(llhs, lrhs, ""),
+          BinaryOp::Mul => self.builder.build_int_mul(llhs, lrhs, ""),
+          _ => {
+            let cmp = self.builder.build_int_compare(int_predicate(*op, unsigned), llhs, lrhs, "");
+            self.builder.build_int_z_extend(cmp, i8_type, "")
+          }
+        }
+      },
+      ExprKind::Divide(lhs, op, rhs, by_zero, overflow) => {
+        let llhs = self.translate_expr(lhs);
+        let lrhs = self.translate_expr(rhs);
+        let ity = lrhs.get_type();
+        let is_zero = self.builder.build_int_compare(IntPredicate::EQ, lrhs, ity.const_zero(), "");
+        self.trap_if(is_zero, by_zero);
+        if let Some(overflow) = overflow {
+          let min = ity.const_int(1 << (ity.get_bit_width() - 1), false);
+          let is_min = self.builder.build_int_compare(IntPredicate::EQ, llhs, min, "");
+          let is_minus_one = self.builder.build_int_compare(IntPredicate::EQ, lrhs, ity.const_all_ones(), "");
+          let overflows = self.builder.build_and(is_min, is_minus_one, "");
+          self.trap_if(overflows, overflow);
+        }
+        match (op, lhs.ty.is_unsigned()) {
+          (DivOp::Div, false) => self.builder.build_int_signed_div(llhs, lrhs, ""),
+          (DivOp::Div, true) => self.builder.build_int_unsigned_div(llhs, lrhs, ""),
+          (DivOp::Rem, false) => self.builder.build_int_signed_rem(llhs, lrhs, ""),
+          (DivOp::Rem, true) => self.builder.build_int_unsigned_rem(llhs, lrhs, ""),
+        }
+      },
+      ExprKind::Cast(val) => {
+        let v = self.translate_expr(val);
+        self.cast(v, val.ty, ty)
+      },
+      ExprKind::If(cond, then, otherwise) => {
+        let c = self.translate_expr(cond);
+        let then_block = self.context.append_basic_block(self.function, "");
+        let else_block = self.context.append_basic_block(self.function, "");
+        let merge_block = self.context.append_basic_block(self.function, "");
+        let is_zero = self.builder.build_int_compare(IntPredicate::EQ, c, c.get_type().const_zero(), "");
+        self.builder.build_conditional_branch(is_zero, else_block, then_block);
+
+        self.builder.position_at_end(then_block);
+        let then_val = self.translate_block(then);
+        let then_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block);
+
+        self.builder.position_at_end(else_block);
+        let else_val = self.translate_block(otherwise);
+        let else_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(merge_block);
+
+        self.builder.position_at_end(merge_block);
+        let phi = self.builder.build_phi(int_type(self.context, ty), "");
+        phi.add_incoming(&[(&then_val, then_end), (&else_val, else_end)]);
+        phi.as_basic_value().into_int_value()
+      },
+      ExprKind::While(cond, stmts) => {
+        let header_block = self.context.append_basic_block(self.function, "");
+        let body_block = self.context.append_basic_block(self.function, "");
+        let exit_block = self.context.append_basic_block(self.function, "");
+        self.builder.build_unconditional_branch(header_block);
+
+        self.builder.position_at_end(header_block);
+        let c = self.translate_expr(cond);
+        let is_zero = self.builder.build_int_compare(IntPredicate::EQ, c, c.get_type().const_zero(), "");
+        self.builder.build_conditional_branch(is_zero, exit_block, body_block);
+
+        self.builder.position_at_end(body_block);
+        self.loops.push((header_block, exit_block));
+        self.translate_block(stmts);
+        self.loops.pop();
+        self.builder.build_unconditional_branch(header_block);
+
+        self.builder.position_at_end(exit_block);
+        i64_type.const_zero()
+      },
+      ExprKind::Break | ExprKind::Continue => {
+        let (header_block, exit_block) = *self.loops.last().unwrap();
+        let target = if let ExprKind::Break = expr.kind { exit_block } else { header_block };
+        self.builder.build_unconditional_branch(target);
+        self.switch_to_unreachable();
+        i64_type.const_zero()
+      },
+      ExprKind::Return(value) => {
+        let r = self.translate_expr(value);
+        self.builder.build_return(Some(&r));
+        self.switch_to_unreachable();
+        i64_type.const_zero()
+      },
+      ExprKind::Call(func, args) => {
+        let vals: Vec<_> = args.iter().map(|arg| self.translate_expr(arg).into()).collect();
+        let call = self.builder.build_call(self.functions[func.0], &vals, "");
+        call.try_as_basic_value().left().unwrap().into_int_value()
+      },
+      ExprKind::Let(id, value) | ExprKind::Assign(id, value) => {
+        let val = self.translate_expr(value);
+        self.builder.build_store(self.locals[id.0], val);
+        val
       }
-      
-      panic!("Invalid generated function!")
+      ExprKind::Global(_) | ExprKind::SetGlobal(..) => unreachable!(),
     }
-    
+  }
 
+  fn translate_block(&mut self, stmts: &[Expr]) -> IntValue<'ctx> {
+    let mut ret = self.context.i64_type().const_zero();
+    for expr in stmts {
+      ret = self.translate_expr(expr);
+    }
+    ret
   }
 
-  pub fn compile(
-    context: &'ctx Context,
-    builder: &'a Builder<'ctx>,
-    pass_manager: &'a PassManager<FunctionValue<'ctx>>,
-    module: &'a Module<'ctx>,
-    main: &Expr
-  ) -> Result<FunctionValue<'ctx>, LangError> {  
-    let mut compiler = Compiler {
-      context,
-      builder,
-      fpm: pass_manager,
-      module,
-    };
+  fn switch_to_unreachable(&mut self) {
+    let block = self.context.append_basic_block(self.function, "");
+    self.builder.position_at_end(block);
+  }
 
-    compiler.compile_main(main)
+  // Variables live in stack slots at the top of the entry block, where
+  // mem2reg can promote them back into registers.
+  fn alloca(&self, ty: Type) -> PointerValue<'ctx> {
+    let builder = self.context.create_builder();
+    let entry = self.function.get_first_basic_block().unwrap();
+    match entry.get_first_instruction() {
+      Some(first) => builder.position_before(&first),
+      None => builder.position_at_end(entry),
+    }
+    builder.build_alloca(int_type(self.context, ty), "")
+  }
+
+  fn cast(&self, val: IntValue<'ctx>, from: Type, to: Type) -> IntValue<'ctx> {
+    let tty = int_type(self.context, to);
+    if from.bits() == to.bits() {
+      val
+    } else if from.bits() > to.bits() {
+      self.builder.build_int_truncate(val, tty, "")
+    } else if from.is_unsigned() {
+      self.builder.build_int_z_extend(val, tty, "")
+    } else {
+      self.builder.build_int_s_extend(val, tty, "")
+    }
+  }
+
+  // Writes the trap's report and aborts when `cond` is true.
+  fn trap_if(&mut self, cond: IntValue<'ctx>, trap: &Trap) {
+    let msg = &trap.report;
+    let size_type = self.context.ptr_sized_int_type(self.target_data, None);
+    let trap_block = self.context.append_basic_block(self.function, "");
+    let ok_block = self.context.append_basic_block(self.function, "");
+    self.builder.build_conditional_branch(cond, trap_block, ok_block);
+
+    self.builder.position_at_end(trap_block);
+    let msg_ptr = self.builder.build_global_string_ptr(msg, "").as_pointer_value();
+    let fd = self.context.i32_type().const_int(2, false);
+    let len = size_type.const_int(msg.len() as u64, false);
+    self.builder.build_call(self.runtime.write, &[fd.into(), msg_ptr.into(), len.into()], "");
+    self.builder.build_call(self.runtime.abort, &[], "");
+    self.builder.build_unreachable();
+
+    self.builder.position_at_end(ok_block);
   }
 }
 
-pub fn code_gen(input: &str) -> Result<(), LangError> {
-  let context = Context::create();
-  let module = context.create_module("execute");
-  let builder = context.create_builder();
-
-  let fpm = PassManager::create(&module);
-
-  fpm.add_instruction_combining_pass();
-  fpm.add_reassociate_pass();
-  fpm.add_gvn_pass();
-  fpm.add_cfg_simplification_pass();
-  fpm.add_basic_alias_analysis_pass();
-  fpm.add_promote_memory_to_register_pass();
-  fpm.add_instruction_combining_pass();
-  fpm.add_reassociate_pass();
-
-  {
-    let parsed = lang::parse::parse(input)?;
-    let expr = &parsed[0];
-
-    let module = context.create_module("tmp");
-    let f = Compiler::compile(&context, &builder, &fpm, &module, expr)?;
-    
-    f.print_to_stderr();
-    
-    let triple = TargetMachine::get_default_triple();
-    let target = Target::from_triple(&triple).unwrap();
-    let target_machine = target.create_target_machine(&triple, "generic", "", OptimizationLevel::None, RelocMode::Default, CodeModel::Default).unwrap();
-
-    
-    module.set_data_layout(&target_machine.get_target_data().get_data_layout());
-    module.set_triple(&triple);
-    
-    target_machine.add_analysis_passes(&fpm);
-    target_machine.write_to_file(&module, FileType::Object, Path::new("output.o")).unwrap();
-    
-    fpm.initialize();
+fn int_type(context: &Context, ty: Type) -> IntType<'_> {
+  context.custom_width_int_type(ty.bits())
+}
+
+fn int_predicate(op: BinaryOp, unsigned: bool) -> IntPredicate {
+  match (op, unsigned) {
+    (BinaryOp::Eq, _) => IntPredicate::EQ,
+    (BinaryOp::Ne, _) => IntPredicate::NE,
+    (BinaryOp::Lt, false) => IntPredicate::SLT,
+    (BinaryOp::Le, false) => IntPredicate::SLE,
+    (BinaryOp::Gt, false) => IntPredicate::SGT,
+    (BinaryOp::Ge, false) => IntPredicate::SGE,
+    (BinaryOp::Lt, true) => IntPredicate::ULT,
+    (BinaryOp::Le, true) => IntPredicate::ULE,
+    (BinaryOp::Gt, true) => IntPredicate::UGT,
+    (BinaryOp::Ge, true) => IntPredicate::UGE,
+    _ => unreachable!("{} isn't a comparison", op.symbol()),
   }
-  
-  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Session;
 
-}
\ No newline at end of file
+  #[test]
+  fn elf_object_for_aarch64() {
+    let session = Session::new(include_str!("../example.sfd")).backend("llvm").target("aarch64-unknown-linux-gnu").optimize(true);
+    let artifacts = session.compile().unwrap();
+    assert!(artifacts.ir.contains("define internal i64 @sfd_test(i64 %0, i64 %1)"), "{}", artifacts.ir);
+    assert_eq!(&artifacts.object[..4], b"\x7fELF");
+    assert_eq!(u16::from_le_bytes([artifacts.object[18], artifacts.object[19]]), 0xb7);
+  }
+}
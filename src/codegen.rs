@@ -1,239 +1,903 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::hash::Hash;
-use std::io::Write;
-use std::ops::Deref;
-use std::path::Path;
-
-use cranelift::codegen::binemit::{NullTrapSink, NullStackMapSink};
-use cranelift::codegen::ir::Function;
-use cranelift::codegen::{Context, verify_function, binemit};
+use std::str::FromStr;
+
+use cranelift::codegen::Context;
+use cranelift::codegen::ir::UserFuncName;
 use cranelift::frontend::{FunctionBuilderContext, FunctionBuilder, Variable};
-use cranelift::prelude::{Signature, AbiParam, Value, settings, ExternalName, EntityRef};
-use cranelift::prelude::isa::{CallConv, TargetIsa};
-use cranelift::prelude::isa;
+use cranelift::prelude::{AbiParam, Value, Block, settings, Configurable, EntityRef, TrapCode, IntCC, MemFlags};
+use cranelift::prelude::isa::{self, OwnedTargetIsa};
 use cranelift::prelude::types::*;
-use cranelift_module::{Module, DataContext, Linkage};
+use cranelift_module::{Module, Linkage, FuncId, DataId, ModuleError, DataDescription};
 use cranelift::prelude::InstBuilder;
 use cranelift_object::{ObjectModule, ObjectBuilder};
+use cranelift_jit::{JITModule, JITBuilder};
+use target_lexicon::{Triple, BinaryFormat};
 
-use crate::lang::parse::{Expr, ExprKind, self};
-use crate::lang::{self, error::{LangError, lang_error_fatal, Span}};
+use crate::backend::{self, Backend, Output};
+use crate::lang::hir::{self, BinaryOp, DivOp, Expr, ExprKind, Function, GlobalId, Program, Trap, UnaryOp};
+use crate::lang::error::{LangError, lang_error_fatal, lang_error_global, Span};
 
-pub struct CodeGen {
+pub struct CodeGen<M: Module = ObjectModule> {
   builder_context: FunctionBuilderContext,
-  data_ctx: DataContext,
   ctx: Context,
-  module: ObjectModule,
+  module: M,
+  functions: Vec<FuncId>, // indexed by the program's FuncIds
+  ir: String, // CLIF of every function defined so far, unless JIT compiling
+  runtime: Option<Runtime>, // only when JIT compiling
+}
+
+// What JIT compiled code shares with the host. It lives as long as the
+// module, so functions and globals carry over from one program to the next.
+struct Runtime {
+  // Aborting would take the host down too, so a trap records which one it
+  // was here, counting from one, and every function returns in turn.
+  trapped: DataId,
+  traps: Vec<LangError>,
+  storage: HashMap<Span, DataId>, // of every global so far, by its `let`
+  globals: Vec<DataId>, // the current program's, indexed by GlobalId
 }
 
-impl CodeGen {
-  fn sig() -> Signature {
-    Signature::new(CallConv::SystemV)
+fn lookup_isa(triple: Triple, flag_builder: settings::Builder) -> Result<OwnedTargetIsa, LangError> {
+  let unsupported = |e: &dyn std::fmt::Display| lang_error_global(&format!("Unsupported target '{}': {}", triple, e));
+  let isa_builder = isa::lookup(triple.clone()).map_err(|e| unsupported(&e))?;
+  isa_builder.finish(settings::Flags::new(flag_builder)).map_err(|e| unsupported(&e))
+}
+
+impl CodeGen<ObjectModule> {
+  // Targets the host when no triple is given. The triple's architecture picks
+  // the ISA and default calling convention, and its binary format (ELF,
+  // Mach-O or COFF) the kind of object file emitted.
+  pub fn new(target: Option<&str>) -> Result<Self, LangError> {
+    Self::with_optimization(target, false)
   }
 
-  pub fn new() -> Self {
-    let flags = settings::Flags::new(settings::builder());
-    let isa_builder = isa::lookup_by_name("x86_64-elf").unwrap();
-    let isa = isa_builder.finish(flags.clone());
-    let obj_builder = ObjectBuilder::new(isa, "output.o", cranelift_module::default_libcall_names()).unwrap();
-    Self {
+  // Like `new`, but lets Cranelift optimize the code it generates for speed.
+  pub fn with_optimization(target: Option<&str>, optimize: bool) -> Result<Self, LangError> {
+    let triple = match target {
+      Some(t) => Triple::from_str(t)
+        .map_err(|e| lang_error_global(&format!("Invalid target '{}': {}", t, e)))?,
+      None => Triple::host(),
+    };
+
+    let mut flag_builder = settings::builder();
+    // COFF has no GOT to go through, cranelift-object can't relocate PIC
+    // aarch64 code in it
+    if triple.binary_format != BinaryFormat::Coff {
+      flag_builder.set("is_pic", "true").unwrap();
+    }
+    if optimize {
+      flag_builder.set("opt_level", "speed").unwrap();
+    }
+    let isa = lookup_isa(triple.clone(), flag_builder)?;
+    let obj_builder = ObjectBuilder::new(isa, "output.o", cranelift_module::default_libcall_names())
+      .map_err(|e| lang_error_global(&format!("Unsupported target '{}': {}", triple, e)))?;
+    Ok(Self {
       builder_context: FunctionBuilderContext::new(),
-      data_ctx: DataContext::new(),
       ctx: Context::new(),
       module: ObjectModule::new(obj_builder),
+      functions: vec![],
+      ir: String::new(),
+      runtime: None,
+    })
+  }
+
+  // Returns the CLIF of every function alongside the emitted object file.
+  pub fn compile(self, program: &Program) -> Result<(String, Vec<u8>), LangError> {
+    match backend::drive(Box::new(self), program)? {
+      Output::Object { ir, object } => Ok((ir, object)),
+      _ => unreachable!("object modules don't run the program"),
+    }
+  }
+}
+
+impl<'a> Backend<'a> for CodeGen<ObjectModule> {
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    self.declare(func)
+  }
+
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    self.define(func)
+  }
+
+  fn emit(self: Box<Self>) -> Result<Output, LangError> {
+    let product = self.module.finish();
+    let object = product.emit()
+      .map_err(|e| lang_error_global(&format!("Failed to emit object file: {}", e)))?;
+    Ok(Output::Object { ir: self.ir, object })
+  }
+}
+
+impl CodeGen<JITModule> {
+  // Compiles for the host, straight into executable memory.
+  pub fn jit() -> Result<Self, LangError> {
+    let mut flag_builder = settings::builder();
+    // calls between JIT'd functions may be too far apart for colocated relocations
+    flag_builder.set("use_colocated_libcalls", "false").unwrap();
+    flag_builder.set("is_pic", "true").unwrap();
+    let isa = lookup_isa(Triple::host(), flag_builder)?;
+    let jit_builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+    let mut module = JITModule::new(jit_builder);
+    let trapped = define_cell(&mut module)?;
+    Ok(Self {
+      builder_context: FunctionBuilderContext::new(),
+      ctx: Context::new(),
+      module,
+      functions: vec![],
+      ir: String::new(),
+      runtime: Some(Runtime { trapped, traps: vec![], storage: HashMap::new(), globals: vec![] }),
+    })
+  }
+
+  // Compiles the program and calls its `main`, returning what it returned.
+  pub fn run(self, program: &Program) -> Result<i64, LangError> {
+    match backend::drive(Box::new(self), program)? {
+      Output::Value(value) => Ok(value),
+      _ => unreachable!("the JIT always runs the program"),
+    }
+  }
+
+  // Compiles a program from `hir::lower_after` into the module and calls its
+  // main, as the REPL does with every input. Functions from earlier programs
+  // aren't compiled again, and globals keep their values between programs.
+  pub fn eval(&mut self, program: &Program) -> Result<i64, LangError> {
+    let runtime = self.runtime.as_mut().unwrap();
+    runtime.globals.clear();
+    for global in &program.globals {
+      let data = match runtime.storage.get(&global.span) {
+        Some(&data) => data,
+        None => define_cell(&mut self.module)?,
+      };
+      runtime.storage.insert(global.span, data);
+      runtime.globals.push(data);
+    }
+
+    let functions = &program.functions[self.functions.len()..];
+    for func in functions {
+      self.declare(func)?;
+    }
+    for func in functions {
+      self.define(func)?;
+    }
+    let main = self.functions.pop().unwrap();
+    self.call_main(main)
+  }
+
+  // Calls a main the module has just defined, turning a trap into its error.
+  fn call_main(&mut self, main: FuncId) -> Result<i64, LangError> {
+    self.module.finalize_definitions()
+      .map_err(|e| lang_error_global(&format!("Code generation failed: {}", e)))?;
+    let main = self.module.get_finalized_function(main);
+    // SAFETY: main is always defined with the signature `fn() -> i64`
+    let main: extern "C" fn() -> i64 = unsafe { std::mem::transmute(main) };
+    let value = main();
+
+    let runtime = self.runtime.as_ref().unwrap();
+    let (trapped, _) = self.module.get_finalized_data(runtime.trapped);
+    // SAFETY: the cell is an aligned i64 that nothing else touches until
+    // JIT code runs again
+    let trapped = unsafe { &mut *(trapped as *mut i64) };
+    match std::mem::take(trapped) {
+      0 => Ok(value),
+      n => Err(runtime.traps[n as usize - 1].clone()),
+    }
+  }
+}
+
+impl<'a> Backend<'a> for CodeGen<JITModule> {
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    self.declare(func)
+  }
+
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    self.define(func)
+  }
+
+  fn emit(mut self: Box<Self>) -> Result<Output, LangError> {
+    let main = self.functions.pop().unwrap();
+    Ok(Output::Value(self.call_main(main)?))
+  }
+}
+
+impl<M: Module> CodeGen<M> {
+  // main is the only function visible outside of the module, the others are
+  // mangled so they can't clash with the runtime's imports. Every program
+  // the JIT runs brings a main of its own, so there it goes unnamed.
+  fn declare(&mut self, func: &Function) -> Result<(), LangError> {
+    let mut sig = self.module.make_signature();
+    for param in func.params() {
+      sig.params.push(AbiParam::new(code_type(param.ty)));
     }
+    sig.returns.push(AbiParam::new(code_type(func.ret)));
+
+    let id = match func.is_main() {
+      true if self.runtime.is_some() => self.module.declare_anonymous_function(&sig),
+      true => self.module.declare_function("main", Linkage::Export, &sig),
+      false => self.module.declare_function(&hir::mangle(&func.name), Linkage::Local, &sig),
+    };
+    let id = id.map_err(|e| module_error(e, func.span))?;
+    self.functions.push(id);
+    Ok(())
   }
-  
-  fn translate(&mut self, exprs: Vec<Expr>) -> Result<String, LangError> {
-    self.ctx.func.signature.returns.push(AbiParam::new(I64));
-    
+
+  fn define(&mut self, func: &Function) -> Result<(), LangError> {
+    let id = self.functions[func.id.0];
+    self.ctx.func.name = UserFuncName::user(0, id.as_u32());
+    self.ctx.func.signature = self.module.declarations().get_function_decl(id).signature.clone();
+
     let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder.seal_block(entry);
+
+    for (i, local) in func.locals.iter().enumerate() {
+      builder.declare_var(Variable::new(i), code_type(local.ty));
+    }
+    let args = builder.block_params(entry).to_vec();
+    for (i, arg) in args.into_iter().enumerate() {
+      builder.def_var(Variable::new(i), arg);
+    }
 
-    let entry_block = builder.create_block();
-    builder.append_block_params_for_function_params(entry_block);
-    builder.switch_to_block(entry_block);
-    builder.seal_block(entry_block);
-    
-    let mut buf = HashMap::new();
-    declare_variables(&mut builder, exprs.as_slice(), &mut 0, &mut buf);
     let mut translator = FunctionTranslator {
+      functions: &self.functions,
       builder,
-      variables: buf,
       module: &mut self.module,
-      ir: String::new(),
+      loops: vec![],
+      runtime: self.runtime.as_mut(),
     };
-    
 
-    
-    let mut ret: Option<Value> = None;
-    for expr in exprs {
-      ret = Some(translator.translate_expr(expr)?);
-    }
-    
-    let r = ret.unwrap_or(translator.builder.ins().iconst(I64, 0));
+    let r = translator.translate_block(&func.body)?;
     translator.builder.ins()
       .return_(&[r]);
-    
     translator.builder.finalize();
-    Ok(translator.ir)
-  }
-  
-  pub fn compile(mut self, src: &str) -> Result<(), LangError> {
-    let parsed = lang::parse::parse(src)?;
-    let ir = self.translate(parsed)?;
-    let mut file = File::create(Path::new("gen.clir")).unwrap();
-    write!(&mut file, "{}", ir).unwrap();
-    write!(&mut file, "{}", self.ctx.func.display().to_string()).unwrap();
-    
-    let id = self.module
-      .declare_function("main", Linkage::Export, &self.ctx.func.signature)
-      .unwrap();
-    
+
     self.module
-      .define_function(id, &mut self.ctx, &mut NullTrapSink{}, &mut NullStackMapSink{})
-      .unwrap();
-      
-    self.module.clear_context(&mut self.ctx);
-    
-    let product = self.module.finish();
-    let mut file = File::create(Path::new("output.o")).unwrap();
-    let emitted = product.emit().unwrap();
-    file.write(emitted.as_slice()).unwrap();
+      .define_function(id, &mut self.ctx)
+      .map_err(|e| module_error(e, func.span))?;
+    if self.runtime.is_none() {
+      self.ir += &self.ctx.func.display().to_string();
+      if !func.is_main() {
+        self.ir += "\n";
+      }
+    }
 
+    self.module.clear_context(&mut self.ctx);
     Ok(())
   }
 }
 
-struct FunctionTranslator<'a> {
-  variables: HashMap<Vec<String>, Variable>,
+struct FunctionTranslator<'a, M: Module> {
+  functions: &'a [FuncId],
   builder: FunctionBuilder<'a>,
-  module: &'a mut ObjectModule,
-  ir: String,
+  module: &'a mut M,
+  loops: Vec<(Block, Block)>, // (header, exit) of each enclosing loop
+  runtime: Option<&'a mut Runtime>,
 }
 
-impl<'a> FunctionTranslator<'a> {
-  fn translate_expr(&mut self, expr: Expr) -> Result<Value, LangError> {
-    match expr.kind {
-      ExprKind::Number(num) => {
-        let imm: i64 = num.parse().unwrap();
-        Ok(self.builder.ins().iconst(I64, i64::from(imm)))
+impl<'a, M: Module> FunctionTranslator<'a, M> {
+  fn translate_expr(&mut self, expr: &Expr) -> Result<Value, LangError> {
+    let span = expr.span;
+    match &expr.kind {
+      // 32 bit immediates are given as the signed value with the same bits
+      ExprKind::Int(n) if expr.ty.bits() == 32 => Ok(self.builder.ins().iconst(I32, *n as i32 as i64)),
+      ExprKind::Int(n) => Ok(self.builder.ins().iconst(code_type(expr.ty), *n)),
+      ExprKind::Bool(b) => Ok(self.builder.ins().iconst(I8, *b as i64)),
+      ExprKind::Local(id) => Ok(self.builder.use_var(Variable::new(id.0))),
+      ExprKind::Unary(op, rhs) => {
+        let val = self.translate_expr(rhs)?;
+        Ok(match op {
+          UnaryOp::Not => self.builder.ins().icmp_imm(IntCC::Equal, val, 0),
+          UnaryOp::Neg => self.builder.ins().ineg(val),
+          UnaryOp::BitNot => self.builder.ins().bnot(val),
+        })
       },
-      ExprKind::BinaryInfix(lhs, op, rhs) => {
-        let llhs = self.translate_expr(*lhs)?;
-        let lrhs = self.translate_expr(*rhs)?;
-        match op.as_str() {
-          "+" => {
-            Ok(self.builder.ins().iadd(llhs, lrhs))
-          }
-          _ => Err(lang_error_fatal("Unknown infix operator", expr.span)),
-        }
+      ExprKind::Binary(lhs, op, rhs) => {
+        let unsigned = lhs.ty.is_unsigned();
+        let llhs = self.translate_expr(lhs)?;
+        let lrhs = self.translate_expr(rhs)?;
+        Ok(match op {
+          BinaryOp::Add => self.builder.ins().iadd(llhs, lrhs),
+          BinaryOp::Sub => self.builder.ins().isub(llhs, lrhs),
+          BinaryOp::Mul => self.builder.ins().imul(llhs, lrhs),
+          _ => self.builder.ins().icmp(int_cc(*op, unsigned), llhs, lrhs),
+        })
       },
-      ExprKind::FuncDef(namespaced, params, ret_type, stmts) => {
-        let mut ctx = Context::new();
-        for (_, ty) in params.clone() {
-          ctx.func.signature.params.push(AbiParam::new(sfdtype_to_code_type(ty).unwrap()));
-        }
-        
-        if let Some(r) = ret_type {
-          ctx.func.signature.returns.push(AbiParam::new(sfdtype_to_code_type(r).unwrap()));
-        } else {
-          ctx.func.signature.returns.push(AbiParam::new(I64));
-        }
-        
-        let mut bctx = FunctionBuilderContext::new();
-        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut bctx);
-        let entry = builder.create_block();
-        builder.append_block_params_for_function_params(entry);
-        builder.switch_to_block(entry);
-        builder.seal_block(entry);
-        
-        let mut buf = self.variables.clone();
-
-        let mut i = 0;
-        for (name, ty) in params {
-          let var = Variable::new(i);
-          buf.insert(vec![name], var);
-          builder.declare_var(var, sfdtype_to_code_type(ty).unwrap());
-          i += 1;
+      ExprKind::Divide(lhs, op, rhs, by_zero, overflow) => {
+        let llhs = self.translate_expr(lhs)?;
+        let lrhs = self.translate_expr(rhs)?;
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, lrhs, 0);
+        self.trap_if(is_zero, by_zero, TrapCode::INTEGER_DIVISION_BY_ZERO, span)?;
+        if let Some(overflow) = overflow {
+          let min = i64::MIN >> (64 - lhs.ty.bits());
+          let is_min = self.builder.ins().icmp_imm(IntCC::Equal, llhs, min);
+          let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, lrhs, -1);
+          let overflows = self.builder.ins().band(is_min, is_minus_one);
+          self.trap_if(overflows, overflow, TrapCode::INTEGER_OVERFLOW, span)?;
         }
+        Ok(match (op, lhs.ty.is_unsigned()) {
+          (DivOp::Div, false) => self.builder.ins().sdiv(llhs, lrhs),
+          (DivOp::Div, true) => self.builder.ins().udiv(llhs, lrhs),
+          (DivOp::Rem, false) => self.builder.ins().srem(llhs, lrhs),
+          (DivOp::Rem, true) => self.builder.ins().urem(llhs, lrhs),
+        })
+      },
+      ExprKind::Cast(val) => {
+        let v = self.translate_expr(val)?;
+        Ok(self.cast(v, val.ty, expr.ty))
+      },
+      ExprKind::If(cond, then, otherwise) => {
+        let c = self.translate_expr(cond)?;
+        let then_block = self.builder.create_block();
+        let else_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
+        let result = self.builder.append_block_param(merge_block, code_type(expr.ty));
+        self.builder.ins().brif(c, then_block, &[], else_block, &[]);
+        self.builder.seal_block(then_block);
+        self.builder.seal_block(else_block);
 
-        declare_variables(&mut builder, &stmts, &mut i, &mut buf);
-        
-        
-        let mut trans = FunctionTranslator {
-          variables: buf,
-          builder,
-          module: self.module,
-          ir: String::new(),
-        };
-        
-        let mut ret: Option<Value> = None;
-        for expr in stmts {
-          ret = Some(trans.translate_expr(expr)?);
-        }
-        
-        let r = ret.unwrap_or(trans.builder.ins().iconst(I64, 0));
-        trans.builder.ins()
-          .return_(&[r]);
-      
-        trans.builder.finalize();
-        
-        let id = self.module
-          .declare_function(&namespaced[0], Linkage::Local, &ctx.func.signature)
-          .unwrap();
-        
-        self.module
-          .define_function(id, &mut ctx, &mut NullTrapSink{}, &mut NullStackMapSink{})
-          .unwrap();
-        
-        self.ir += &(ctx.func.display().to_string() + "\n");
-        
-        self.module.clear_context(&mut ctx);
-        
-        // let vfs = self.variables.get(&namespaced).unwrap();
-        // self.builder.def_var(*vfs, Value::from_u32(id.as_u32()));
-        
+        self.builder.switch_to_block(then_block);
+        let val = self.translate_block(then)?;
+        self.builder.ins().jump(merge_block, &[val]);
+
+        self.builder.switch_to_block(else_block);
+        let val = self.translate_block(otherwise)?;
+        self.builder.ins().jump(merge_block, &[val]);
+        self.builder.seal_block(merge_block);
+
+        self.builder.switch_to_block(merge_block);
+        Ok(result)
+      },
+      ExprKind::While(cond, stmts) => {
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+        self.builder.ins().jump(header_block, &[]);
+
+        self.builder.switch_to_block(header_block);
+        let c = self.translate_expr(cond)?;
+        self.builder.ins().brif(c, body_block, &[], exit_block, &[]);
+        self.builder.seal_block(body_block);
+
+        self.builder.switch_to_block(body_block);
+        self.loops.push((header_block, exit_block));
+        self.translate_block(stmts)?;
+        self.loops.pop();
+        self.builder.ins().jump(header_block, &[]);
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(exit_block);
+
+        self.builder.switch_to_block(exit_block);
         Ok(self.builder.ins().iconst(I64, 0))
-      }
-      ExprKind::FuncCall(namespaced, args) => {
-        if !self.variables.contains_key(&namespaced) {
-          return Err(lang_error_fatal("Undefined function", expr.span));
+      },
+      ExprKind::Break | ExprKind::Continue => {
+        let (header_block, exit_block) = *self.loops.last().unwrap();
+        let target = if let ExprKind::Break = expr.kind { exit_block } else { header_block };
+        self.builder.ins().jump(target, &[]);
+        self.switch_to_unreachable();
+        Ok(self.builder.ins().iconst(I64, 0))
+      },
+      ExprKind::Return(value) => {
+        let r = self.translate_expr(value)?;
+        self.builder.ins().return_(&[r]);
+        self.switch_to_unreachable();
+        Ok(self.builder.ins().iconst(I64, 0))
+      },
+      ExprKind::Call(func, args) => {
+        let mut vals = vec![];
+        for arg in args {
+          vals.push(self.translate_expr(arg)?);
         }
-        
-        let fvar = self.variables.get(&namespaced).unwrap();
-        unimplemented!()
+
+        let callee = self.module.declare_func_in_func(self.functions[func.0], self.builder.func);
+        let call = self.builder.ins().call(callee, &vals);
+        let result = self.builder.inst_results(call)[0];
+        self.return_if_trapped();
+        Ok(result)
       },
-      ExprKind::Symbol(sym) => {
-        let var = self.variables.get(&sym)
-          .ok_or(lang_error_fatal("Undefined variable", expr.span))?;
-        Ok(self.builder.use_var(*var))
+      ExprKind::Let(id, value) | ExprKind::Assign(id, value) => {
+        let val = self.translate_expr(value)?;
+        self.builder.def_var(Variable::new(id.0), val);
+        Ok(val)
+      }
+      ExprKind::Global(id) => {
+        let addr = self.global_addr(*id);
+        Ok(self.builder.ins().load(code_type(expr.ty), MemFlags::new(), addr, 0))
+      }
+      ExprKind::SetGlobal(id, value) => {
+        let val = self.translate_expr(value)?;
+        let addr = self.global_addr(*id);
+        self.builder.ins().store(MemFlags::new(), val, addr, 0);
+        Ok(val)
       }
-      _ => unimplemented!()
     }
   }
 }
 
-fn declare_variables(builder: &mut FunctionBuilder, exprs: &[Expr], index: &mut usize, vars: &mut HashMap<Vec<String>, Variable>) {
-  for expr in exprs {
-    match expr.clone().kind {
-      ExprKind::FuncDef(namespaced, params, ret_type, _) => {
-        if !vars.contains_key(&namespaced) {
-          let var = Variable::new(*index);
-          vars.insert(namespaced, var);
-          builder.declare_var(var, I64);
-          *index += 1;
-        }
+impl<'a, M: Module> FunctionTranslator<'a, M> {
+  fn translate_block(&mut self, stmts: &[Expr]) -> Result<Value, LangError> {
+    let mut ret = None;
+    for expr in stmts {
+      ret = Some(self.translate_expr(expr)?);
+    }
+    Ok(ret.unwrap())
+  }
+
+  // Gives the dead code following a jump out somewhere to go.
+  fn switch_to_unreachable(&mut self) {
+    let block = self.builder.create_block();
+    self.builder.switch_to_block(block);
+    self.builder.seal_block(block);
+  }
+
+  // Cranelift's integer types don't record signedness, so extending goes by
+  // `from`.
+  fn cast(&mut self, val: Value, from: hir::Type, to: hir::Type) -> Value {
+    let (fty, tty) = (code_type(from), code_type(to));
+    if fty == tty {
+      val
+    } else if fty.bits() > tty.bits() {
+      self.builder.ins().ireduce(tty, val)
+    } else if from.is_unsigned() {
+      self.builder.ins().uextend(tty, val)
+    } else {
+      self.builder.ins().sextend(tty, val)
+    }
+  }
+
+  fn data_addr(&mut self, data: DataId) -> Value {
+    let gv = self.module.declare_data_in_func(data, self.builder.func);
+    let ptr = self.module.target_config().pointer_type();
+    self.builder.ins().symbol_value(ptr, gv)
+  }
+
+  fn global_addr(&mut self, id: GlobalId) -> Value {
+    let data = self.runtime.as_ref().expect("only the JIT has globals").globals[id.0];
+    self.data_addr(data)
+  }
+
+  fn return_zero(&mut self) {
+    let ty = self.builder.func.signature.returns[0].value_type;
+    let zero = self.builder.ins().iconst(ty, 0);
+    self.builder.ins().return_(&[zero]);
+  }
+
+  // Passes a trap in a callee on to the caller, see `Runtime`.
+  fn return_if_trapped(&mut self) {
+    let trapped = match &self.runtime {
+      Some(runtime) => runtime.trapped,
+      None => return,
+    };
+    let addr = self.data_addr(trapped);
+    let n = self.builder.ins().load(I64, MemFlags::new(), addr, 0);
+    let trap_block = self.builder.create_block();
+    let ok_block = self.builder.create_block();
+    self.builder.ins().brif(n, trap_block, &[], ok_block, &[]);
+    self.builder.seal_block(trap_block);
+    self.builder.seal_block(ok_block);
+
+    self.builder.switch_to_block(trap_block);
+    self.return_zero();
+    self.builder.switch_to_block(ok_block);
+  }
+
+  fn import_function(&mut self, name: &str, params: &[Type], returns: &[Type], span: Span) -> Result<FuncId, LangError> {
+    let mut sig = self.module.make_signature();
+    sig.params.extend(params.iter().map(|&t| AbiParam::new(t)));
+    sig.returns.extend(returns.iter().map(|&t| AbiParam::new(t)));
+    self.module
+      .declare_function(name, Linkage::Import, &sig)
+      .map_err(|e| module_error(e, span))
+  }
+
+  // Traps when `cond` is nonzero, see `Runtime` for how the JIT does.
+  fn trap_if(&mut self, cond: Value, trap: &Trap, code: TrapCode, span: Span) -> Result<(), LangError> {
+    let trap_block = self.builder.create_block();
+    let ok_block = self.builder.create_block();
+    self.builder.ins().brif(cond, trap_block, &[], ok_block, &[]);
+    self.builder.seal_block(trap_block);
+    self.builder.seal_block(ok_block);
+
+    self.builder.switch_to_block(trap_block);
+    match &mut self.runtime {
+      Some(runtime) => {
+        runtime.traps.push(trap.error.clone());
+        let (trapped, n) = (runtime.trapped, runtime.traps.len() as i64);
+        let addr = self.data_addr(trapped);
+        let n = self.builder.ins().iconst(I64, n);
+        self.builder.ins().store(MemFlags::new(), n, addr, 0);
+        self.return_zero();
       }
-      _ => {}
+      None => self.report_and_abort(trap, code, span)?,
     }
+
+    self.builder.switch_to_block(ok_block);
+    Ok(())
+  }
+
+  // Writes the trap's report to stderr and aborts the process.
+  fn report_and_abort(&mut self, trap: &Trap, code: TrapCode, span: Span) -> Result<(), LangError> {
+    let msg = &trap.report;
+    let mut data_ctx = DataDescription::new();
+    data_ctx.define(msg.as_bytes().into());
+    let data = self.module
+      .declare_anonymous_data(false, false)
+      .map_err(|e| module_error(e, span))?;
+    self.module
+      .define_data(data, &data_ctx)
+      .map_err(|e| module_error(e, span))?;
+
+    let ptr = self.module.target_config().pointer_type();
+    let write = self.import_function("write", &[I32, ptr, ptr], &[ptr], span)?;
+    let abort = self.import_function("abort", &[], &[], span)?;
+
+    let msg_ptr = self.data_addr(data);
+    let fd = self.builder.ins().iconst(I32, 2);
+    let len = self.builder.ins().iconst(ptr, msg.len() as i64);
+    let write = self.module.declare_func_in_func(write, self.builder.func);
+    self.builder.ins().call(write, &[fd, msg_ptr, len]);
+    let abort = self.module.declare_func_in_func(abort, self.builder.func);
+    self.builder.ins().call(abort, &[]);
+    self.builder.ins().trap(code);
+    Ok(())
+  }
+}
+
+// A writable, zeroed i64, for the JIT's runtime.
+fn define_cell<M: Module>(module: &mut M) -> Result<DataId, LangError> {
+  let mut data_ctx = DataDescription::new();
+  data_ctx.define_zeroinit(8);
+  data_ctx.set_align(8);
+  let failed = |e| lang_error_global(&format!("Code generation failed: {}", e));
+  let data = module.declare_anonymous_data(true, false).map_err(failed)?;
+  module.define_data(data, &data_ctx).map_err(failed)?;
+  Ok(data)
+}
+
+fn module_error(err: ModuleError, span: Span) -> LangError {
+  lang_error_fatal(&format!("Code generation failed: {}", err), span)
+}
+
+fn code_type(ty: hir::Type) -> Type {
+  match ty.bits() {
+    8 => I8,
+    32 => I32,
+    _ => I64,
+  }
+}
+
+fn int_cc(op: BinaryOp, unsigned: bool) -> IntCC {
+  match (op, unsigned) {
+    (BinaryOp::Eq, _) => IntCC::Equal,
+    (BinaryOp::Ne, _) => IntCC::NotEqual,
+    (BinaryOp::Lt, false) => IntCC::SignedLessThan,
+    (BinaryOp::Le, false) => IntCC::SignedLessThanOrEqual,
+    (BinaryOp::Gt, false) => IntCC::SignedGreaterThan,
+    (BinaryOp::Ge, false) => IntCC::SignedGreaterThanOrEqual,
+    (BinaryOp::Lt, true) => IntCC::UnsignedLessThan,
+    (BinaryOp::Le, true) => IntCC::UnsignedLessThanOrEqual,
+    (BinaryOp::Gt, true) => IntCC::UnsignedGreaterThan,
+    (BinaryOp::Ge, true) => IntCC::UnsignedGreaterThanOrEqual,
+    _ => unreachable!("only comparisons have a condition code"),
   }
 }
 
-fn sfdtype_to_code_type(ty: String) -> Option<Type> {
-  match ty.as_str() {
-    "Int32" => Some(I32),
-    "Int64" => Some(I64),
-    _ => unimplemented!("Add custom types!")
+#[cfg(test)]
+mod tests {
+  use super::CodeGen;
+  use crate::interp::Interpreter;
+  use crate::lang::hir::Program;
+  use crate::Session;
+
+  fn x86_64_linux() -> CodeGen {
+    CodeGen::new(Some("x86_64-unknown-linux-gnu")).unwrap()
+  }
+
+  fn lower(src: &str) -> Program {
+    Session::new(src).check().unwrap()
+  }
+
+  fn jit(src: &str) -> i64 {
+    CodeGen::jit().unwrap().run(&lower(src)).unwrap()
+  }
+
+  fn clif(src: &str) -> String {
+    x86_64_linux().compile(&lower(src)).unwrap().0
+  }
+
+  // Calls and the division's report need relocations in every format.
+  fn object(target: &str) -> Vec<u8> {
+    let src = "def half(a Int64) -> Int64\n  a / 2\nend\nhalf(84)\n";
+    CodeGen::new(Some(target)).unwrap().compile(&lower(src)).unwrap().1
+  }
+
+  #[test]
+  fn parameters_are_bound_to_entry_block() {
+    let ir = clif(include_str!("../example.sfd"));
+    let expected = "\
+function u0:0(i64, i64) -> i64 system_v {
+block0(v0: i64, v1: i64):
+    v2 = iadd v0, v1
+    return v2
+}
+";
+    assert!(ir.starts_with(expected), "{}", ir);
+  }
+
+  #[test]
+  fn int32_parameters_are_converted_at_uses() {
+    let ir = clif("\
+def add(a Int32, b Int64) -> Int64
+  let c Int64 = a
+  c + b + 1
+end
+add(1, 2)
+");
+    assert!(ir.contains("function u0:0(i32, i64) -> i64 system_v"), "{}", ir);
+    assert!(ir.contains("sextend.i64 v0"), "{}", ir);
+  }
+
+  #[test]
+  fn elf_targets() {
+    let targets = [("x86_64-unknown-linux-gnu", 0x3e), ("aarch64-unknown-linux-gnu", 0xb7), ("riscv64gc-unknown-linux-gnu", 0xf3)];
+    for (target, machine) in targets {
+      let obj = object(target);
+      assert_eq!(&obj[..4], b"\x7fELF", "{}", target);
+      assert_eq!(u16::from_le_bytes([obj[18], obj[19]]), machine, "{}", target);
+    }
+  }
+
+  #[test]
+  fn macho_targets() {
+    for (target, cpu) in [("x86_64-apple-darwin", 0x0100_0007), ("aarch64-apple-darwin", 0x0100_000c)] {
+      let obj = object(target);
+      assert_eq!(u32::from_le_bytes([obj[0], obj[1], obj[2], obj[3]]), 0xfeed_facf, "{}", target);
+      assert_eq!(u32::from_le_bytes([obj[4], obj[5], obj[6], obj[7]]), cpu, "{}", target);
+    }
+  }
+
+  #[test]
+  fn coff_targets() {
+    for (target, machine) in [("x86_64-pc-windows-msvc", 0x8664), ("aarch64-pc-windows-msvc", 0xaa64)] {
+      let obj = object(target);
+      assert_eq!(u16::from_le_bytes([obj[0], obj[1]]), machine, "{}", target);
+    }
+  }
+
+  #[test]
+  fn unsupported_targets() {
+    assert!(CodeGen::new(Some("mips-unknown-linux-gnu")).is_err());
+    assert!(CodeGen::new(Some("not-a-target")).is_err());
+  }
+
+  #[test]
+  fn jit_runs_main() {
+    let src = "def twice(a Int32) -> Int32\n  a * 2\nend\ntwice(21)\n";
+    let jit = CodeGen::jit().unwrap();
+    assert_eq!(jit.run(&lower(src)).unwrap(), 42);
+  }
+
+  #[test]
+  fn jit_calls_functions_defined_after_the_call_site() {
+    // parity calls even?, and even? and odd? call each other, before they're defined
+    let src = "\
+def parity(n Int64) -> Int64
+  if even?(n)
+    0
+  else
+    1
+  end
+end
+def even?(n Int64) -> Bool
+  if n == 0
+    true
+  else
+    odd?(n - 1)
+  end
+end
+def odd?(n Int64) -> Bool
+  if n == 0
+    false
+  else
+    even?(n - 1)
+  end
+end
+parity(10) * 10 + parity(7)
+";
+    assert_eq!(jit(src), 1);
+  }
+
+  #[test]
+  fn arithmetic_wraps_at_the_operand_width() {
+    let cases = [
+      ("Int32", "7 - 10", -3),
+      ("Int64", "7 - 10", -3),
+      ("Int32", "65536 * 65536", 0),
+      ("Int64", "65536 * 65536", 4294967296),
+      ("Int32", "-7 / 2", -3),
+      ("Int64", "-7 / 2", -3),
+      ("Int32", "-7 % 2", -1),
+      ("Int64", "-7 % 2", -1),
+      ("UInt32", "4294967295 / 2", 2147483647),
+      ("UInt32", "4294967295 % 10", 5),
+      ("Int32", "2147483647 + 1", -2147483648),
+      // only a signed MIN over -1 overflows, not the same bits unsigned
+      ("Int32", "-2147483647 / -1", 2147483647),
+      ("Int64", "-9223372036854775807 % -1", 0),
+      ("UInt32", "2147483648 / 4294967295", 0),
+    ];
+    for (ty, expr, expected) in cases {
+      // the operands are parameters, so nothing is folded before codegen
+      let (a, rest) = expr.split_once(' ').unwrap();
+      let (op, b) = rest.split_once(' ').unwrap();
+      let src = format!("def f(a {0}, b {0}) -> {0}\n  a {1} b\nend\nlet r Int64 = f({2}, {3})\nr\n", ty, op, a, b);
+      assert_eq!(jit(&src), expected, "{} on {}", expr, ty);
+    }
+  }
+
+  #[test]
+  fn logical_operators_short_circuit() {
+    // the right hand side only runs, and assigns, when it decides the result
+    let src = "\
+var n = 0
+let a = false and (n = n + 1) == 1
+let b = true or (n = n + 10) == 10
+let c = true and (n = n + 100) == 100
+let d = false or (n = n + 1000) == 1100
+n
+";
+    assert_eq!(jit(src), 1100);
+  }
+
+  #[test]
+  fn elsif_chains_pick_the_first_true_branch() {
+    let src = "\
+def sign(n Int64) -> Int64
+  if n < 0
+    -1
+  elsif n == 0
+    0
+  elsif n > 1000
+    2
+  else
+    1
+  end
+end
+let missing = if false
+  5
+end
+sign(-5) * 1000 + sign(0) * 100 + sign(7) * 10 + sign(5000) + missing
+";
+    assert_eq!(jit(src), -1000 + 10 + 2);
+  }
+
+  #[test]
+  fn break_and_continue_leave_the_innermost_loop() {
+    // sums the odd numbers below 10, for each of three outer iterations
+    let src = "\
+var total = 0
+var outer = 0
+while outer < 3
+  outer = outer + 1
+  var i = 0
+  while true
+    i = i + 1
+    if i >= 10
+      break
+    elsif i % 2 == 0
+      continue
+    end
+    total = total + i
+  end
+end
+total
+";
+    assert_eq!(jit(src), 75);
+  }
+
+  #[test]
+  fn shadowing_and_reassignment() {
+    let src = "\
+let x = 1
+let x = x + 10
+var y = x
+y = y * 2
+if true
+  let x = 1000
+  y = y + x
+end
+x + y
+";
+    assert_eq!(jit(src), 11 + 1022);
+  }
+
+  #[test]
+  fn groups_and_prefix_operators() {
+    let src = "\
+def f(a Int32, b Int32, c Int32) -> Int32
+  (a + b) * c - -a + ~b
+end
+f(2, 3, 4)
+";
+    assert_eq!(jit(src), (2 + 3) * 4 + 2 + !3);
+  }
+
+  #[test]
+  fn return_leaves_nested_blocks() {
+    let src = "\
+def first_square_over(n Int64) -> Int64
+  var i = 0
+  while true
+    if i * i > n
+      return i
+    end
+    i = i + 1
+  end
+  -1
+end
+def nothing() -> Int64
+  return
+  7
+end
+first_square_over(50) * 10 + nothing()
+";
+    assert_eq!(jit(src), 80);
+  }
+
+  #[test]
+  fn literals_at_the_edges_of_their_type() {
+    let src = "\
+let a Int32 = -2147483648
+let b UInt64 = 18446744073709551615
+let c Int64 = -9223372036854775808
+let d UInt32 = 4294967295
+(a + 1 == -2147483647) and (b + 1 == 0) and (c - 1 == 9223372036854775807) and (d + 1 == 0)
+";
+    let program = lower(src);
+    assert_eq!(CodeGen::jit().unwrap().run(&program).unwrap(), 1);
+    assert_eq!(Interpreter::new().run(&program).unwrap(), 1);
+  }
+
+  #[test]
+  fn jit_reports_traps_instead_of_aborting() {
+    let src = "\
+def f(a Int32) -> Int32
+  a / (a - 3)
+end
+def g(a Int32) -> Int32
+  f(a) + 100
+end
+g(3)
+";
+    let err = CodeGen::jit().unwrap().run(&lower(src)).unwrap_err();
+    assert_eq!((err.msg.as_str(), &src[err.span.start..err.span.end]), ("Division by zero", "a / (a - 3)"));
+
+    let src = "def f(a Int64, b Int64) -> Int64\n  a / b\nend\nf(-9223372036854775807 - 1, -1)\n";
+    let err = CodeGen::jit().unwrap().run(&lower(src)).unwrap_err();
+    assert_eq!((err.msg.as_str(), &src[err.span.start..err.span.end]), ("Division overflow", "a / b"));
+  }
+
+  #[test]
+  fn jit_matches_interpreter() {
+    let src = "\
+def wrap(a Int32, b Int32) -> Int64
+  let c Int32 = 2147483647
+  c + a + b
+end
+var i = 0
+var n = 0
+while true
+  i = i + 1
+  if i % 2 == 0
+    continue
+  elsif i > 15
+    break
+  end
+  n = n + i
+end
+n * wrap(1, 2) / -7 + ~0
+";
+    let program = lower(src);
+    let expected = Interpreter::new().run(&program).unwrap();
+    assert_eq!(CodeGen::jit().unwrap().run(&program).unwrap(), expected);
   }
 }
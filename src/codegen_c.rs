@@ -0,0 +1,292 @@
+use std::fmt::Write;
+
+use crate::backend::{Backend, Output};
+use crate::lang::hir::{self, Expr, ExprKind, Function, Type, UnaryOp};
+use crate::lang::error::LangError;
+
+const PRELUDE: &str = "\
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static void scaffold_trap(const char *msg) {
+  fputs(msg, stderr);
+  abort();
+}
+";
+
+// Emits a single C99 translation unit. Arithmetic goes through unsigned types
+// so it wraps the way it does in the native backends, and divisions trap on
+// the operands the native backends trap on, rather than either overflowing
+// into undefined behaviour.
+pub struct Transpiler {
+  functions: Vec<String>, // mangled names, indexed by the program's FuncIds
+  prototypes: String,
+  definitions: String,
+}
+
+impl Transpiler {
+  pub fn new() -> Self {
+    Transpiler { functions: vec![], prototypes: String::new(), definitions: String::new() }
+  }
+}
+
+impl Default for Transpiler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<'a> Backend<'a> for Transpiler {
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let name = hir::mangle(&func.name);
+    let param_list = if func.params == 0 {
+      "void".to_string()
+    } else {
+      func.params().iter().map(|p| c_type(p.ty)).collect::<Vec<_>>().join(", ")
+    };
+    writeln!(self.prototypes, "static {} {}({});", c_type(func.ret), name, param_list).unwrap();
+    self.functions.push(name);
+    Ok(())
+  }
+
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let mut translator = FunctionTranslator {
+      functions: &self.functions,
+      locals: &func.locals,
+      names: vec![String::new(); func.locals.len()],
+      lines: vec![],
+      indent: 1,
+      next_name: 0,
+    };
+
+    let mut params = vec![];
+    for (i, param) in func.params().iter().enumerate() {
+      let c_name = translator.variable_name(&param.name);
+      params.push(format!("{} {}", c_type(param.ty), c_name));
+      translator.names[i] = c_name;
+    }
+    let param_list = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+
+    let r = translator.translate_block(&func.body);
+    translator.line(format!("return {};", r));
+
+    writeln!(self.definitions, "\nstatic {} {}({}) {{", c_type(func.ret), self.functions[func.id.0], param_list).unwrap();
+    for line in translator.lines {
+      writeln!(self.definitions, "{}", line).unwrap();
+    }
+    writeln!(self.definitions, "}}").unwrap();
+    Ok(())
+  }
+
+  fn emit(self: Box<Self>) -> Result<Output, LangError> {
+    let main = self.functions.last().unwrap();
+    let mut source = format!("{}\n{}{}", PRELUDE, self.prototypes, self.definitions);
+    write!(source, "\nint main(void) {{\n  return (int){}();\n}}\n", main).unwrap();
+    Ok(Output::Source(source))
+  }
+}
+
+struct FunctionTranslator<'a> {
+  functions: &'a [String],
+  locals: &'a [hir::Local],
+  names: Vec<String>, // C name of each local, once it's declared
+  lines: Vec<String>,
+  indent: usize,
+  next_name: usize, // numbers every variable and temporary in the function
+}
+
+impl<'a> FunctionTranslator<'a> {
+  // Every expression is evaluated into a temporary, so side effects happen in
+  // the same order as they're written. Yields a C expression for the value.
+  fn translate_expr(&mut self, expr: &Expr) -> String {
+    let ty = expr.ty;
+    match &expr.kind {
+      ExprKind::Int(n) => match ty {
+        Type::Int32 => format!("INT32_C({})", n),
+        Type::UInt32 => format!("UINT32_C({})", n),
+        Type::UInt64 => format!("UINT64_C({})", *n as u64),
+        // the literal would be out of range before it's negated
+        _ if *n == i64::MIN => "INT64_MIN".to_string(),
+        _ => format!("INT64_C({})", n),
+      },
+      ExprKind::Bool(b) => (*b as u8).to_string(),
+      ExprKind::Local(id) => {
+        let name = self.names[id.0].clone();
+        self.temp(ty, name)
+      }
+      ExprKind::Unary(op, rhs) => {
+        let val = self.translate_expr(rhs);
+        let (ct, ut) = (c_type(ty), unsigned_c_type(ty));
+        match op {
+          UnaryOp::Not => self.temp(ty, format!("{} == 0", val)),
+          UnaryOp::Neg => self.temp(ty, format!("({})-({}){}", ct, ut, val)),
+          UnaryOp::BitNot => self.temp(ty, format!("({})~({}){}", ct, ut, val)),
+        }
+      },
+      ExprKind::Binary(lhs, op, rhs) => {
+        let llhs = self.translate_expr(lhs);
+        let lrhs = self.translate_expr(rhs);
+        let (ct, ut) = (c_type(lhs.ty), unsigned_c_type(lhs.ty));
+        let sym = op.symbol();
+        let val = if op.is_comparison() {
+          format!("({}){} {} ({}){}", ct, llhs, sym, ct, lrhs)
+        } else {
+          format!("({})(({}){} {} ({}){})", ct, ut, llhs, sym, ut, lrhs)
+        };
+        self.temp(ty, val)
+      },
+      ExprKind::Divide(lhs, op, rhs, by_zero, overflow) => {
+        let llhs = self.translate_expr(lhs);
+        let lrhs = self.translate_expr(rhs);
+        let ct = c_type(lhs.ty);
+        self.line(format!("if ({} == 0) scaffold_trap({});", lrhs, c_string(&by_zero.report)));
+        if let Some(overflow) = overflow {
+          let min = if lhs.ty.bits() == 32 { "INT32_MIN" } else { "INT64_MIN" };
+          self.line(format!("if ({} == -1 && {} == {}) scaffold_trap({});", lrhs, llhs, min, c_string(&overflow.report)));
+        }
+        self.temp(ty, format!("({}){} {} ({}){}", ct, llhs, op.symbol(), ct, lrhs))
+      },
+      // C's integer conversions already truncate, or sign or zero extend
+      // depending on the source type, the same way the native backends cast
+      ExprKind::Cast(val) => {
+        let val = self.translate_expr(val);
+        format!("({}){}", c_type(ty), val)
+      },
+      ExprKind::If(cond, then, otherwise) => {
+        let result = self.fresh_name("t");
+        self.line(format!("{} {};", c_type(ty), result));
+        let c = self.translate_expr(cond);
+        self.open(format!("if ({}) {{", c));
+        let val = self.translate_block(then);
+        self.line(format!("{} = {};", result, val));
+        self.close("} else {");
+        self.indent += 1;
+        let val = self.translate_block(otherwise);
+        self.line(format!("{} = {};", result, val));
+        self.close("}");
+        result
+      },
+      ExprKind::While(cond, stmts) => {
+        self.open("for (;;) {".to_string());
+        let c = self.translate_expr(cond);
+        self.line(format!("if (!{}) break;", c));
+        self.translate_block(stmts);
+        self.close("}");
+        "INT64_C(0)".to_string()
+      },
+      ExprKind::Break | ExprKind::Continue => {
+        self.line(if let ExprKind::Break = expr.kind { "break;" } else { "continue;" }.to_string());
+        "INT64_C(0)".to_string()
+      },
+      ExprKind::Return(value) => {
+        let r = self.translate_expr(value);
+        self.line(format!("return {};", r));
+        "INT64_C(0)".to_string()
+      },
+      ExprKind::Call(func, args) => {
+        let vals: Vec<String> = args.iter().map(|arg| self.translate_expr(arg)).collect();
+        let call = format!("{}({})", self.functions[func.0], vals.join(", "));
+        self.temp(ty, call)
+      },
+      ExprKind::Let(id, value) => {
+        let val = self.translate_expr(value);
+        let c_name = self.variable_name(&self.locals[id.0].name);
+        self.line(format!("{} {} = {};", c_type(ty), c_name, val));
+        self.names[id.0] = c_name;
+        val
+      }
+      ExprKind::Assign(id, value) => {
+        let val = self.translate_expr(value);
+        self.line(format!("{} = {};", self.names[id.0], val));
+        val
+      }
+      ExprKind::Global(_) | ExprKind::SetGlobal(..) => unreachable!(),
+    }
+  }
+
+  fn translate_block(&mut self, stmts: &[Expr]) -> String {
+    let mut ret = String::new();
+    for expr in stmts {
+      ret = self.translate_expr(expr);
+    }
+    ret
+  }
+
+  fn temp(&mut self, ty: Type, value: String) -> String {
+    let name = self.fresh_name("t");
+    self.line(format!("{} {} = {};", c_type(ty), name, value));
+    name
+  }
+
+  fn fresh_name(&mut self, prefix: &str) -> String {
+    self.next_name += 1;
+    format!("{}{}", prefix, self.next_name)
+  }
+
+  fn variable_name(&mut self, name: &str) -> String {
+    self.next_name += 1;
+    format!("{}_{}", hir::escape_identifier(name), self.next_name)
+  }
+
+  fn line(&mut self, line: String) {
+    self.lines.push(format!("{}{}", "  ".repeat(self.indent), line));
+  }
+
+  fn open(&mut self, line: String) {
+    self.line(line);
+    self.indent += 1;
+  }
+
+  fn close(&mut self, line: &str) {
+    self.indent -= 1;
+    self.line(line.to_string());
+  }
+}
+
+fn c_type(ty: Type) -> &'static str {
+  match ty {
+    Type::Int32 => "int32_t",
+    Type::Int64 => "int64_t",
+    Type::UInt32 => "uint32_t",
+    Type::UInt64 => "uint64_t",
+    Type::Bool => "int8_t",
+  }
+}
+
+fn unsigned_c_type(ty: Type) -> &'static str {
+  match ty.bits() {
+    8 => "uint8_t",
+    32 => "uint32_t",
+    _ => "uint64_t",
+  }
+}
+
+fn c_string(s: &str) -> String {
+  let mut lit = String::from("\"");
+  for b in s.bytes() {
+    match b {
+      b'"' => lit.push_str("\\\""),
+      b'\\' => lit.push_str("\\\\"),
+      b'\n' => lit.push_str("\\n"),
+      b'\t' => lit.push_str("\\t"),
+      b' '..=b'~' => lit.push(b as char),
+      b => write!(lit, "\\{:03o}", b).unwrap(),
+    }
+  }
+  lit.push('"');
+  lit
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Session;
+
+  #[test]
+  fn namespaced_functions_are_mangled() {
+    let session = Session::new("def math::twice(a Int32) -> Int32\n  a * 2\nend\nmath::twice(21)\n");
+    let c = session.transpile(&session.check().unwrap()).unwrap();
+    assert!(c.contains("static int32_t sfd_math_Ntwice(int32_t a_1) {"), "{}", c);
+    assert!(c.contains("int main(void) {\n  return (int)sfd_main();\n}"), "{}", c);
+  }
+}
@@ -0,0 +1,541 @@
+use std::fmt::Write;
+
+use crate::backend::{Backend, Output};
+use crate::lang::hir::{BinaryOp, DivOp, Expr, ExprKind, Function, Type, UnaryOp};
+use crate::lang::error::LangError;
+
+// Emits a standalone WebAssembly module, exporting every function under its
+// name, namespaces joined with `::`, and the top level as `main`. Int32,
+// UInt32 and Bool are i32 in wasm, Int64 and UInt64 are i64. The module has no
+// imports, so runtime errors such as division by zero are wasm traps.
+pub struct WasmGen {
+  functions: Vec<FuncDecl>, // indexed by the program's FuncIds, as in the module
+  types: Vec<(Vec<ValType>, ValType)>,
+  bodies: Vec<(Vec<ValType>, Vec<Instr>)>, // locals and code, in declaration order
+}
+
+struct FuncDecl {
+  name: String,
+  type_index: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValType {
+  I32,
+  I64,
+}
+
+impl ValType {
+  fn byte(self) -> u8 {
+    match self {
+      ValType::I32 => 0x7f,
+      ValType::I64 => 0x7e,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      ValType::I32 => "i32",
+      ValType::I64 => "i64",
+    }
+  }
+}
+
+enum Instr {
+  I32Const(i32),
+  I64Const(i64),
+  LocalGet(u32),
+  LocalTee(u32),
+  Call(u32),
+  Drop,
+  Block,
+  Loop,
+  If(ValType),
+  Else,
+  End,
+  Br(u32),
+  BrIf(u32),
+  Return,
+  Op(&'static str, u8), // any instruction without immediates
+}
+
+impl WasmGen {
+  pub fn new() -> Self {
+    WasmGen { functions: vec![], types: vec![], bodies: vec![] }
+  }
+
+  // The module in the text format, the IR printed alongside the binary.
+  fn text(&self) -> String {
+    let mut out = String::from("(module\n");
+    for ((locals, code), decl) in self.bodies.iter().zip(&self.functions) {
+      let (params, ret) = &self.types[decl.type_index as usize];
+      write!(out, "  (func ${} (export \"{}\")", decl.name, decl.name).unwrap();
+      for p in params {
+        write!(out, " (param {})", p.name()).unwrap();
+      }
+      writeln!(out, " (result {})", ret.name()).unwrap();
+      if !locals.is_empty() {
+        let locals: Vec<&str> = locals.iter().map(|l| l.name()).collect();
+        writeln!(out, "    (local {})", locals.join(" ")).unwrap();
+      }
+
+      let mut indent = 2;
+      for instr in code {
+        if let Instr::Else | Instr::End = instr {
+          indent -= 1;
+        }
+        let text = match instr {
+          Instr::I32Const(v) => format!("i32.const {}", v),
+          Instr::I64Const(v) => format!("i64.const {}", v),
+          Instr::LocalGet(i) => format!("local.get {}", i),
+          Instr::LocalTee(i) => format!("local.tee {}", i),
+          Instr::Call(i) => format!("call {}", i),
+          Instr::Drop => "drop".to_string(),
+          Instr::Block => "block".to_string(),
+          Instr::Loop => "loop".to_string(),
+          Instr::If(ty) => format!("if (result {})", ty.name()),
+          Instr::Else => "else".to_string(),
+          Instr::End => "end".to_string(),
+          Instr::Br(d) => format!("br {}", d),
+          Instr::BrIf(d) => format!("br_if {}", d),
+          Instr::Return => "return".to_string(),
+          Instr::Op(name, _) => name.to_string(),
+        };
+        writeln!(out, "{}{}", "  ".repeat(indent), text).unwrap();
+        if let Instr::Block | Instr::Loop | Instr::If(_) | Instr::Else = instr {
+          indent += 1;
+        }
+      }
+      out.truncate(out.len() - 1);
+      out.push_str(")\n");
+    }
+    out.push_str(")\n");
+    out
+  }
+
+  fn encode(&self) -> Vec<u8> {
+    let mut module = b"\0asm".to_vec();
+    module.extend(1u32.to_le_bytes());
+
+    let mut types = vec![];
+    uleb(&mut types, self.types.len() as u64);
+    for (params, ret) in &self.types {
+      types.push(0x60);
+      uleb(&mut types, params.len() as u64);
+      types.extend(params.iter().map(|p| p.byte()));
+      types.extend([1, ret.byte()]);
+    }
+    section(&mut module, 1, &types);
+
+    let mut funcs = vec![];
+    uleb(&mut funcs, self.functions.len() as u64);
+    for decl in &self.functions {
+      uleb(&mut funcs, decl.type_index as u64);
+    }
+    section(&mut module, 3, &funcs);
+
+    let mut exports = vec![];
+    uleb(&mut exports, self.functions.len() as u64);
+    for (index, decl) in self.functions.iter().enumerate() {
+      uleb(&mut exports, decl.name.len() as u64);
+      exports.extend(decl.name.as_bytes());
+      exports.push(0x00); // a function
+      uleb(&mut exports, index as u64);
+    }
+    section(&mut module, 7, &exports);
+
+    let mut code = vec![];
+    uleb(&mut code, self.bodies.len() as u64);
+    for (locals, instrs) in &self.bodies {
+      let mut body = vec![];
+      uleb(&mut body, locals.len() as u64);
+      for local in locals {
+        body.extend([1, local.byte()]);
+      }
+      for instr in instrs {
+        encode_instr(&mut body, instr);
+      }
+      body.push(0x0b);
+      uleb(&mut code, body.len() as u64);
+      code.extend(body);
+    }
+    section(&mut module, 10, &code);
+    module
+  }
+}
+
+impl Default for WasmGen {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<'a> Backend<'a> for WasmGen {
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let sig = (func.params().iter().map(|p| val_type(p.ty)).collect(), val_type(func.ret));
+    let type_index = match self.types.iter().position(|t| *t == sig) {
+      Some(i) => i,
+      None => {
+        self.types.push(sig);
+        self.types.len() - 1
+      }
+    } as u32;
+    self.functions.push(FuncDecl { name: func.name.join("::"), type_index });
+    Ok(())
+  }
+
+  // Locals are numbered the same as in the program, parameters first, and
+  // followed by the translator's scratch locals.
+  fn define_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    let mut translator = FunctionTranslator {
+      code: vec![],
+      depth: 0,
+      loops: vec![],
+      first_scratch: func.locals.len() as u32,
+      scratch: vec![],
+    };
+    translator.translate_block(&func.body);
+    let mut locals: Vec<ValType> = func.locals[func.params..].iter().map(|l| val_type(l.ty)).collect();
+    locals.extend(translator.scratch);
+    self.bodies.push((locals, translator.code));
+    Ok(())
+  }
+
+  fn emit(self: Box<Self>) -> Result<Output, LangError> {
+    Ok(Output::Object { ir: self.text(), object: self.encode() })
+  }
+}
+
+struct FunctionTranslator {
+  code: Vec<Instr>,
+  depth: u32, // how many blocks, loops and ifs enclose the current instruction
+  loops: Vec<u32>, // depth of each enclosing loop's outer block
+  first_scratch: u32, // index of the first local the program doesn't have
+  scratch: Vec<ValType>,
+}
+
+impl FunctionTranslator {
+  // Every expression leaves exactly one value on the stack.
+  fn translate_expr(&mut self, expr: &Expr) {
+    let ty = expr.ty;
+    match &expr.kind {
+      ExprKind::Int(n) => self.push_const(ty, *n),
+      ExprKind::Bool(b) => self.code.push(Instr::I32Const(*b as i32)),
+      ExprKind::Local(id) => self.code.push(Instr::LocalGet(id.0 as u32)),
+      ExprKind::Unary(op, rhs) => {
+        self.translate_expr(rhs);
+        let wide = val_type(rhs.ty) == ValType::I64;
+        match op {
+          UnaryOp::Not => {
+            self.code.push(if wide { Instr::Op("i64.eqz", 0x50) } else { Instr::Op("i32.eqz", 0x45) });
+          }
+          // -x and ~x are x * -1 and x ^ -1, which wrap the same way
+          UnaryOp::Neg => {
+            self.push_const(ty, -1);
+            self.code.push(if wide { Instr::Op("i64.mul", 0x7e) } else { Instr::Op("i32.mul", 0x6c) });
+          }
+          UnaryOp::BitNot => {
+            self.push_const(ty, -1);
+            self.code.push(if wide { Instr::Op("i64.xor", 0x85) } else { Instr::Op("i32.xor", 0x73) });
+          }
+        }
+      },
+      ExprKind::Binary(lhs, op, rhs) => {
+        self.translate_expr(lhs);
+        self.translate_expr(rhs);
+        self.code.push(binary_op(*op, lhs.ty));
+      },
+      ExprKind::Divide(lhs, op, rhs, _, overflow) => {
+        // div_s traps on the overflow itself but rem_s yields 0, so the
+        // remainder's operands are divided too, for the trap
+        if let (DivOp::Rem, Some(_)) = (op, overflow) {
+          let (l, r) = (self.scratch(lhs.ty), self.scratch(lhs.ty));
+          self.translate_expr(lhs);
+          self.code.push(Instr::LocalTee(l));
+          self.translate_expr(rhs);
+          self.code.push(Instr::LocalTee(r));
+          self.code.push(div_op(DivOp::Rem, lhs.ty));
+          self.code.extend([Instr::LocalGet(l), Instr::LocalGet(r), div_op(DivOp::Div, lhs.ty), Instr::Drop]);
+        } else {
+          self.translate_expr(lhs);
+          self.translate_expr(rhs);
+          self.code.push(div_op(*op, lhs.ty));
+        }
+      },
+      ExprKind::Cast(val) => {
+        self.translate_expr(val);
+        self.cast(val.ty, ty);
+      },
+      ExprKind::If(cond, then, otherwise) => {
+        self.translate_expr(cond);
+        self.open(Instr::If(val_type(ty)));
+        self.translate_block(then);
+        self.code.push(Instr::Else);
+        self.translate_block(otherwise);
+        self.close();
+      },
+      ExprKind::While(cond, stmts) => {
+        self.open(Instr::Block);
+        self.loops.push(self.depth);
+        self.open(Instr::Loop);
+        self.translate_expr(cond);
+        self.code.push(Instr::Op("i32.eqz", 0x45));
+        self.code.push(Instr::BrIf(1));
+        self.translate_block(stmts);
+        self.code.push(Instr::Drop);
+        self.code.push(Instr::Br(0));
+        self.close();
+        self.loops.pop();
+        self.close();
+        self.code.push(Instr::I64Const(0));
+      },
+      ExprKind::Break | ExprKind::Continue => {
+        let block = *self.loops.last().unwrap();
+        // the loop sits just inside the block a break leaves
+        let target = if let ExprKind::Break = expr.kind { block } else { block + 1 };
+        self.code.push(Instr::Br(self.depth - target));
+        self.code.push(Instr::I64Const(0));
+      },
+      ExprKind::Return(value) => {
+        self.translate_expr(value);
+        self.code.push(Instr::Return);
+        self.code.push(Instr::I64Const(0));
+      },
+      ExprKind::Call(func, args) => {
+        for arg in args {
+          self.translate_expr(arg);
+        }
+        self.code.push(Instr::Call(func.0 as u32));
+      },
+      ExprKind::Let(id, value) | ExprKind::Assign(id, value) => {
+        self.translate_expr(value);
+        self.code.push(Instr::LocalTee(id.0 as u32));
+      }
+      ExprKind::Global(_) | ExprKind::SetGlobal(..) => unreachable!(),
+    }
+  }
+
+  // Leaves the value of the block's last statement.
+  fn translate_block(&mut self, stmts: &[Expr]) {
+    for (i, expr) in stmts.iter().enumerate() {
+      if i > 0 {
+        self.code.push(Instr::Drop);
+      }
+      self.translate_expr(expr);
+    }
+  }
+
+  fn cast(&mut self, from: Type, to: Type) {
+    match (val_type(from), val_type(to)) {
+      (ValType::I32, ValType::I64) if from.is_unsigned() => self.code.push(Instr::Op("i64.extend_i32_u", 0xad)),
+      (ValType::I32, ValType::I64) => self.code.push(Instr::Op("i64.extend_i32_s", 0xac)),
+      (ValType::I64, ValType::I32) => self.code.push(Instr::Op("i32.wrap_i64", 0xa7)),
+      _ => {}
+    }
+    // Bools are held sign extended from their low 8 bits, as an int8 would be
+    if to == Type::Bool {
+      self.code.push(Instr::Op("i32.extend8_s", 0xc0));
+    }
+  }
+
+  fn push_const(&mut self, ty: Type, value: i64) {
+    self.code.push(match val_type(ty) {
+      ValType::I32 => Instr::I32Const(value as i32),
+      ValType::I64 => Instr::I64Const(value),
+    });
+  }
+
+  // A new local, since whatever the current expression nests may be using
+  // the ones before it.
+  fn scratch(&mut self, ty: Type) -> u32 {
+    self.scratch.push(val_type(ty));
+    self.first_scratch + self.scratch.len() as u32 - 1
+  }
+
+  fn open(&mut self, instr: Instr) {
+    self.code.push(instr);
+    self.depth += 1;
+  }
+
+  fn close(&mut self) {
+    self.code.push(Instr::End);
+    self.depth -= 1;
+  }
+}
+
+fn val_type(ty: Type) -> ValType {
+  if ty.bits() == 64 { ValType::I64 } else { ValType::I32 }
+}
+
+fn binary_op(op: BinaryOp, ty: Type) -> Instr {
+  let unsigned = ty.is_unsigned();
+  let (i32_op, i64_op) = match op {
+    BinaryOp::Add => (("i32.add", 0x6a), ("i64.add", 0x7c)),
+    BinaryOp::Sub => (("i32.sub", 0x6b), ("i64.sub", 0x7d)),
+    BinaryOp::Mul => (("i32.mul", 0x6c), ("i64.mul", 0x7e)),
+    BinaryOp::Eq => (("i32.eq", 0x46), ("i64.eq", 0x51)),
+    BinaryOp::Ne => (("i32.ne", 0x47), ("i64.ne", 0x52)),
+    BinaryOp::Lt if unsigned => (("i32.lt_u", 0x49), ("i64.lt_u", 0x54)),
+    BinaryOp::Lt => (("i32.lt_s", 0x48), ("i64.lt_s", 0x53)),
+    BinaryOp::Gt if unsigned => (("i32.gt_u", 0x4b), ("i64.gt_u", 0x56)),
+    BinaryOp::Gt => (("i32.gt_s", 0x4a), ("i64.gt_s", 0x55)),
+    BinaryOp::Le if unsigned => (("i32.le_u", 0x4d), ("i64.le_u", 0x58)),
+    BinaryOp::Le => (("i32.le_s", 0x4c), ("i64.le_s", 0x57)),
+    BinaryOp::Ge if unsigned => (("i32.ge_u", 0x4f), ("i64.ge_u", 0x5a)),
+    BinaryOp::Ge => (("i32.ge_s", 0x4e), ("i64.ge_s", 0x59)),
+  };
+  let (name, opcode) = if val_type(ty) == ValType::I32 { i32_op } else { i64_op };
+  Instr::Op(name, opcode)
+}
+
+fn div_op(op: DivOp, ty: Type) -> Instr {
+  let (i32_op, i64_op) = match (op, ty.is_unsigned()) {
+    (DivOp::Div, true) => (("i32.div_u", 0x6e), ("i64.div_u", 0x80)),
+    (DivOp::Div, false) => (("i32.div_s", 0x6d), ("i64.div_s", 0x7f)),
+    (DivOp::Rem, true) => (("i32.rem_u", 0x70), ("i64.rem_u", 0x82)),
+    (DivOp::Rem, false) => (("i32.rem_s", 0x6f), ("i64.rem_s", 0x81)),
+  };
+  let (name, opcode) = if val_type(ty) == ValType::I32 { i32_op } else { i64_op };
+  Instr::Op(name, opcode)
+}
+
+fn encode_instr(out: &mut Vec<u8>, instr: &Instr) {
+  match instr {
+    Instr::I32Const(v) => {
+      out.push(0x41);
+      sleb(out, *v as i64);
+    }
+    Instr::I64Const(v) => {
+      out.push(0x42);
+      sleb(out, *v);
+    }
+    Instr::LocalGet(i) => {
+      out.push(0x20);
+      uleb(out, *i as u64);
+    }
+    Instr::LocalTee(i) => {
+      out.push(0x22);
+      uleb(out, *i as u64);
+    }
+    Instr::Call(i) => {
+      out.push(0x10);
+      uleb(out, *i as u64);
+    }
+    Instr::Drop => out.push(0x1a),
+    Instr::Block => out.extend([0x02, 0x40]),
+    Instr::Loop => out.extend([0x03, 0x40]),
+    Instr::If(ty) => out.extend([0x04, ty.byte()]),
+    Instr::Else => out.push(0x05),
+    Instr::End => out.push(0x0b),
+    Instr::Br(d) => {
+      out.push(0x0c);
+      uleb(out, *d as u64);
+    }
+    Instr::BrIf(d) => {
+      out.push(0x0d);
+      uleb(out, *d as u64);
+    }
+    Instr::Return => out.push(0x0f),
+    Instr::Op(_, opcode) => out.push(*opcode),
+  }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+  module.push(id);
+  uleb(module, contents.len() as u64);
+  module.extend(contents);
+}
+
+fn uleb(out: &mut Vec<u8>, mut v: u64) {
+  loop {
+    let byte = (v & 0x7f) as u8;
+    v >>= 7;
+    if v == 0 {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+fn sleb(out: &mut Vec<u8>, mut v: i64) {
+  loop {
+    let byte = (v & 0x7f) as u8;
+    v >>= 7;
+    if (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0) {
+      out.push(byte);
+      return;
+    }
+    out.push(byte | 0x80);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::Session;
+
+  fn compile(src: &str) -> Vec<u8> {
+    let module = Session::new(src).target("wasm32-unknown-unknown").compile().unwrap().object;
+    wasmparser::validate(&module).unwrap();
+    module
+  }
+
+  // What the module's main returns, None when it traps.
+  fn run(module: &[u8]) -> Option<i64> {
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, module).unwrap();
+    let mut store = wasmi::Store::new(&engine, ());
+    let instance = wasmi::Linker::new(&engine)
+      .instantiate(&mut store, &module).unwrap()
+      .start(&mut store).unwrap();
+    let main = instance.get_typed_func::<(), i64>(&store, "main").unwrap();
+    main.call(&mut store, ()).ok()
+  }
+
+  fn interpret(src: &str) -> Option<i64> {
+    let session = Session::new(src);
+    session.interpret(&session.check().unwrap()).ok()
+  }
+
+  #[test]
+  fn modules_validate_export_every_function_and_run() {
+    let src = "\
+def math::even?(n Int32) -> Bool
+  n % 2 == 0
+end
+def twice(a Int32) -> Int32
+  var i = 0
+  while true
+    if i == 2 or not math::even?(i)
+      break
+    end
+    i = i + 1
+  end
+  a * i
+end
+twice(21)
+";
+    let module = compile(src);
+    let mut exports = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(&module) {
+      if let wasmparser::Payload::ExportSection(reader) = payload.unwrap() {
+        exports.extend(reader.into_iter().map(|e| e.unwrap().name.to_string()));
+      }
+    }
+    assert_eq!(exports, ["math::even?", "twice", "main"]);
+    assert_eq!(run(&module), interpret(src));
+  }
+
+  #[test]
+  fn divisions_trap_where_the_interpreter_reports_them() {
+    for src in [
+      "let a Int32 = -2147483647 - 1\na % -1\n",
+      "let a Int32 = -2147483647 - 1\na / -1\n",
+      "let a = -9223372036854775807 - 1\na % (a / a - 2)\n",
+      "let a = 7\n(a % (a - 7)) + 1\n",
+      "let a Int32 = -7\nlet b Int32 = 2\n(a % b) * 10 + a / b + (a % (b % 3))\n",
+    ] {
+      assert_eq!(run(&compile(src)), interpret(src), "{}", src);
+    }
+  }
+}
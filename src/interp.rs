@@ -0,0 +1,249 @@
+use crate::backend::{self, Backend, Output};
+use crate::lang::hir::{BinaryOp, DivOp, Expr, ExprKind, Function, Program, Type, UnaryOp};
+use crate::lang::error::{LangError, lang_error_fatal, lang_error_global};
+
+// Evaluates the lowered program directly, following the same rules as the
+// Cranelift backend: values wrap at the width of their type, casts truncate
+// or extend them, and divisions trap where the HIR says they do.
+pub struct Interpreter<'a> {
+  functions: Vec<&'a Function>, // indexed by FuncId
+}
+
+// Values are kept as the raw bits of their type, alongside the type itself.
+#[derive(Clone, Copy)]
+struct Val {
+  bits: u64,
+  ty: Type,
+}
+
+// Anything that stops evaluation from carrying on with the next expression.
+enum Unwind {
+  Error(LangError),
+  Break,
+  Continue,
+  Return(Val),
+}
+
+impl From<LangError> for Unwind {
+  fn from(err: LangError) -> Self {
+    Unwind::Error(err)
+  }
+}
+
+type Eval<T> = Result<T, Unwind>;
+
+// Calls recurse on the host's stack, so programs are evaluated on a thread
+// with room for this many, even with an unoptimized build's frames. The
+// stack is only committed as it's used.
+const MAX_CALL_DEPTH: usize = 20_000;
+const STACK_SIZE: usize = 1 << 30;
+
+struct Frame<'i, 'a> {
+  interp: &'i Interpreter<'a>,
+  slots: Vec<Val>, // indexed by LocalId
+  depth: usize, // how many calls deep the function is
+}
+
+impl<'a> Interpreter<'a> {
+  pub fn new() -> Self {
+    Interpreter { functions: vec![] }
+  }
+
+  // Evaluates the program, returning the value its top level evaluates to.
+  pub fn run(self, program: &'a Program) -> Result<i64, LangError> {
+    match backend::drive(Box::new(self), program)? {
+      Output::Value(value) => Ok(value),
+      _ => unreachable!("the interpreter always runs the program"),
+    }
+  }
+
+  fn call(&self, func: &Function, args: Vec<Val>, depth: usize) -> Eval<Val> {
+    let mut slots: Vec<Val> = func.locals.iter().map(|l| int(0, l.ty)).collect();
+    slots[..args.len()].copy_from_slice(&args);
+    let mut frame = Frame { interp: self, slots, depth };
+    match frame.eval_block(&func.body) {
+      Ok(val) | Err(Unwind::Return(val)) => Ok(val),
+      Err(err) => Err(err),
+    }
+  }
+}
+
+impl Default for Interpreter<'_> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<'a> Backend<'a> for Interpreter<'a> {
+  fn declare_function(&mut self, func: &'a Function) -> Result<(), LangError> {
+    self.functions.push(func);
+    Ok(())
+  }
+
+  // Bodies are kept from when they were declared, and evaluated as they're called.
+  fn define_function(&mut self, _func: &'a Function) -> Result<(), LangError> {
+    Ok(())
+  }
+
+  fn emit(self: Box<Self>) -> Result<Output, LangError> {
+    let main = self.functions.last().unwrap();
+    let result = std::thread::scope(|scope| {
+      let thread = std::thread::Builder::new().stack_size(STACK_SIZE)
+        .spawn_scoped(scope, || self.call(main, vec![], 0))
+        .map_err(|e| lang_error_global(&format!("Couldn't start the interpreter: {}", e)))?;
+      thread.join().map_err(|panic| std::panic::resume_unwind(panic))
+    })?;
+    match result {
+      Ok(val) => Ok(Output::Value(val.bits as i64)),
+      Err(Unwind::Error(err)) => Err(err),
+      Err(_) => unreachable!("functions catch return, and loops break and continue"),
+    }
+  }
+}
+
+impl<'i, 'a> Frame<'i, 'a> {
+  fn eval(&mut self, expr: &Expr) -> Eval<Val> {
+    match &expr.kind {
+      ExprKind::Int(n) => Ok(int(*n as u64, expr.ty)),
+      ExprKind::Bool(b) => Ok(int(*b as u64, Type::Bool)),
+      ExprKind::Local(id) => Ok(self.slots[id.0]),
+      ExprKind::Unary(op, rhs) => {
+        let val = self.eval(rhs)?;
+        Ok(match op {
+          UnaryOp::Not => int((val.bits == 0) as u64, Type::Bool),
+          UnaryOp::Neg => int(val.bits.wrapping_neg(), val.ty),
+          UnaryOp::BitNot => int(!val.bits, val.ty),
+        })
+      },
+      ExprKind::Binary(lhs, op, rhs) => {
+        let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+        let unsigned = lhs.ty.is_unsigned();
+        let (l, r) = (lhs.bits, rhs.bits);
+        let (sl, sr) = (signed(l, lhs.ty), signed(r, lhs.ty));
+        let bits = match op {
+          BinaryOp::Eq => (l == r) as u64,
+          BinaryOp::Ne => (l != r) as u64,
+          BinaryOp::Lt => (if unsigned { l < r } else { sl < sr }) as u64,
+          BinaryOp::Le => (if unsigned { l <= r } else { sl <= sr }) as u64,
+          BinaryOp::Gt => (if unsigned { l > r } else { sl > sr }) as u64,
+          BinaryOp::Ge => (if unsigned { l >= r } else { sl >= sr }) as u64,
+          BinaryOp::Add => l.wrapping_add(r),
+          BinaryOp::Sub => l.wrapping_sub(r),
+          BinaryOp::Mul => l.wrapping_mul(r),
+        };
+        Ok(int(bits, expr.ty))
+      },
+      ExprKind::Divide(lhs, op, rhs, by_zero, overflow) => {
+        let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+        let (l, r) = (lhs.bits, rhs.bits);
+        let (sl, sr) = (signed(l, lhs.ty), signed(r, lhs.ty));
+        if r == 0 {
+          return Err(by_zero.error.clone().into());
+        }
+        if let Some(overflow) = overflow {
+          if sr == -1 && sl == i64::MIN >> (64 - lhs.ty.bits()) {
+            return Err(overflow.error.clone().into());
+          }
+        }
+        let bits = match (op, lhs.ty.is_unsigned()) {
+          (DivOp::Div, false) => (sl / sr) as u64,
+          (DivOp::Div, true) => l / r,
+          (DivOp::Rem, false) => (sl % sr) as u64,
+          (DivOp::Rem, true) => l % r,
+        };
+        Ok(int(bits, expr.ty))
+      },
+      ExprKind::Cast(val) => Ok(cast(self.eval(val)?, expr.ty)),
+      ExprKind::If(cond, then, otherwise) => {
+        if self.eval(cond)?.bits != 0 {
+          self.eval_block(then)
+        } else {
+          self.eval_block(otherwise)
+        }
+      },
+      ExprKind::While(cond, stmts) => {
+        while self.eval(cond)?.bits != 0 {
+          match self.eval_block(stmts) {
+            Ok(_) | Err(Unwind::Continue) => {}
+            Err(Unwind::Break) => break,
+            Err(err) => return Err(err),
+          }
+        }
+        Ok(int(0, Type::Int64))
+      },
+      ExprKind::Break => Err(Unwind::Break),
+      ExprKind::Continue => Err(Unwind::Continue),
+      ExprKind::Return(value) => Err(Unwind::Return(self.eval(value)?)),
+      ExprKind::Let(id, value) | ExprKind::Assign(id, value) => {
+        let val = self.eval(value)?;
+        self.slots[id.0] = val;
+        Ok(val)
+      }
+      ExprKind::Global(_) | ExprKind::SetGlobal(..) => unreachable!(),
+      ExprKind::Call(func, args) => {
+        let mut vals = vec![];
+        for arg in args {
+          vals.push(self.eval(arg)?);
+        }
+        if self.depth == MAX_CALL_DEPTH {
+          return Err(lang_error_fatal("Too many nested calls", expr.span).into());
+        }
+        self.interp.call(self.interp.functions[func.0], vals, self.depth + 1)
+      },
+    }
+  }
+
+  fn eval_block(&mut self, stmts: &[Expr]) -> Eval<Val> {
+    let mut val = int(0, Type::Int64);
+    for expr in stmts {
+      val = self.eval(expr)?;
+    }
+    Ok(val)
+  }
+}
+
+fn int(bits: u64, ty: Type) -> Val {
+  let width = ty.bits();
+  let bits = if width == 64 { bits } else { bits & ((1 << width) - 1) };
+  Val { bits, ty }
+}
+
+// The value as a signed integer, sign extended from the width of its type.
+fn signed(bits: u64, ty: Type) -> i64 {
+  let shift = 64 - ty.bits();
+  ((bits << shift) as i64) >> shift
+}
+
+fn cast(val: Val, to: Type) -> Val {
+  if val.ty.is_unsigned() {
+    int(val.bits, to)
+  } else {
+    int(signed(val.bits, val.ty) as u64, to)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Interpreter;
+  use crate::Session;
+
+  fn run(src: &str) -> Result<i64, String> {
+    let program = Session::new(src).check().unwrap();
+    Interpreter::new().run(&program).map_err(|err| err.msg)
+  }
+
+  #[test]
+  fn signed_division_overflow_traps() {
+    assert_eq!(run("let a Int32 = -2147483647 - 1\na / -1\n"), Err("Division overflow".to_string()));
+    assert_eq!(run("let a = -9223372036854775807 - 1\na % -1\n"), Err("Division overflow".to_string()));
+    assert_eq!(run("let a Int32 = -2147483647 - 1\na / 1\n"), Ok(-2147483648));
+    assert_eq!(run("let a UInt32 = 4294967295\na / 4294967295\n"), Ok(1));
+  }
+
+  #[test]
+  fn deep_recursion_is_an_error() {
+    let count = "def count(n Int64) -> Int64\n  if n == 0\n    0\n  else\n    count(n - 1) + 1\n  end\nend\n";
+    assert_eq!(run(&format!("{}count(10000)\n", count)), Ok(10000));
+    assert_eq!(run(&format!("{}count(200000)\n", count)), Err("Too many nested calls".to_string()));
+  }
+}
@@ -1,4 +1,5 @@
 pub mod error;
 pub mod tokenize;
 pub mod parse;
-pub mod analyse;
\ No newline at end of file
+pub mod analyse;
+pub mod hir;
\ No newline at end of file
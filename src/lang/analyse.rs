@@ -1,8 +1,990 @@
-use super::parse::{Expr, ExprKind, parse};
-use super::error::*;
+use std::collections::HashMap;
 
-struct Analyser {
-  src: String,
-  exprs: Vec<Expr>,
+use super::{
+  error::{IResult, LangError, lang_error, lang_error_noted, lang_errors, lang_note, span, Span},
+  hir::Type,
+  parse::{Expr, ExprKind},
+};
+
+// Identifies a single definition: a function, a parameter or a `let` binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DefId(pub usize);
+
+#[derive(Debug, Clone)]
+pub enum DefKind {
+  Function(Vec<DefId>), // parameters
+  Param,
+  Local(bool), // mutable
+}
+
+#[derive(Debug, Clone)]
+pub struct Def {
+  pub name: Vec<String>,
+  pub kind: DefKind,
+  pub span: Span, // the definition, or the function for a parameter
+}
+
+// What every name in a program refers to. Expressions are looked up by their
+// span, which is unique to each expression.
+#[derive(Debug, Clone)]
+pub struct Resolution {
+  pub defs: Vec<Def>,
+  pub main: DefId, // the implicit function holding the top level of the program
+  ids: HashMap<Span, DefId>,
+}
+
+impl Resolution {
+  pub fn def(&self, id: DefId) -> &Def {
+    &self.defs[id.0]
+  }
+
+  // The definition a `Symbol`, `FuncCall` or `Assign` refers to, or the one
+  // a `Let` or `FuncDef` introduces.
+  pub fn resolve(&self, expr: &Expr) -> Option<DefId> {
+    self.ids.get(&expr.span).copied()
+  }
+}
+
+struct Resolver {
+  resolution: Resolution,
+  functions: HashMap<Vec<String>, DefId>,
+  scopes: Vec<HashMap<String, DefId>>,
+  loops: usize, // how many loops in the current function enclose the expression
+  errors: Vec<LangError>,
+}
+
+impl Resolver {
+  fn define(&mut self, name: Vec<String>, kind: DefKind, span: Span) -> DefId {
+    self.resolution.defs.push(Def { name, kind, span });
+    DefId(self.resolution.defs.len() - 1)
+  }
+
+  // Function names are global, however deeply a definition is nested, so
+  // they're all collected before any body is resolved.
+  fn collect_functions(&mut self, exprs: &[Expr]) {
+    for expr in exprs {
+      match &expr.kind {
+        ExprKind::FuncDef(namespaced, _, _, stmts) => {
+          if namespaced.len() == 1 && namespaced[0] == "main" {
+            self.errors.push(lang_error("'main' is reserved for the top level of the program", expr.span));
+          } else if let Some(&first) = self.functions.get(namespaced) {
+            let name = namespaced.join("::");
+            let note = lang_note(&format!("'{}' is first defined here", name), self.resolution.def(first).span);
+            self.errors.push(lang_error_noted(&format!("Function '{}' is already defined", name), expr.span, vec![note]));
+          } else {
+            let id = self.define(namespaced.clone(), DefKind::Function(vec![]), expr.span);
+            self.functions.insert(namespaced.clone(), id);
+            self.resolution.ids.insert(expr.span, id);
+          }
+          self.collect_functions(stmts);
+        }
+        ExprKind::If(branches, otherwise) => {
+          for (_, stmts) in branches {
+            self.collect_functions(stmts);
+          }
+          if let Some(stmts) = otherwise {
+            self.collect_functions(stmts);
+          }
+        }
+        ExprKind::While(_, stmts) => self.collect_functions(stmts),
+        _ => {}
+      }
+    }
+  }
+
+  // Functions only see their own parameters, not the variables around them.
+  fn resolve_function(&mut self, func: DefId, params: &[(String, String, Span)], stmts: &[Expr]) {
+    let mut scope = HashMap::new();
+    let mut ids = vec![];
+    for (name, _, span) in params {
+      if scope.contains_key(name) {
+        self.errors.push(lang_error(&format!("Parameter '{}' is already defined", name), *span));
+        continue;
+      }
+      let id = self.define(vec![name.clone()], DefKind::Param, *span);
+      scope.insert(name.clone(), id);
+      ids.push(id);
+    }
+    self.resolution.defs[func.0].kind = DefKind::Function(ids);
+
+    let outer = std::mem::replace(&mut self.scopes, vec![scope]);
+    let loops = std::mem::replace(&mut self.loops, 0);
+    for stmt in stmts {
+      self.resolve_expr(stmt);
+    }
+    self.scopes = outer;
+    self.loops = loops;
+  }
+
+  fn resolve_block(&mut self, stmts: &[Expr]) {
+    self.scopes.push(HashMap::new());
+    for stmt in stmts {
+      self.resolve_expr(stmt);
+    }
+    self.scopes.pop();
+  }
+
+  fn lookup_variable(&self, name: &[String]) -> Option<DefId> {
+    match name {
+      [name] => self.scopes.iter().rev().find_map(|s| s.get(name)).copied(),
+      _ => None,
+    }
+  }
+
+  fn resolve_expr(&mut self, expr: &Expr) {
+    match &expr.kind {
+      ExprKind::Number(_) | ExprKind::Bool(_) => {}
+      ExprKind::Break | ExprKind::Continue => {
+        if self.loops == 0 {
+          self.errors.push(lang_error("Can't break or continue outside of a loop", expr.span));
+        }
+      }
+      ExprKind::Symbol(name) => match self.lookup_variable(name) {
+        Some(id) => { self.resolution.ids.insert(expr.span, id); }
+        None => self.errors.push(lang_error(&format!("Undefined variable '{}'", name.join("::")), expr.span)),
+      },
+      ExprKind::UnaryPrefix(_, rhs) => self.resolve_expr(rhs),
+      ExprKind::BinaryInfix(lhs, _, rhs) => {
+        self.resolve_expr(lhs);
+        self.resolve_expr(rhs);
+      }
+      ExprKind::If(branches, otherwise) => {
+        for (cond, stmts) in branches {
+          self.resolve_expr(cond);
+          self.resolve_block(stmts);
+        }
+        if let Some(stmts) = otherwise {
+          self.resolve_block(stmts);
+        }
+      }
+      ExprKind::While(cond, stmts) => {
+        self.resolve_expr(cond);
+        self.loops += 1;
+        self.resolve_block(stmts);
+        self.loops -= 1;
+      }
+      ExprKind::Return(value) => {
+        if let Some(value) = value {
+          self.resolve_expr(value);
+        }
+      }
+      ExprKind::Let(name, _, mutable, value) => {
+        // the value can't see the binding it initializes
+        self.resolve_expr(value);
+        let id = self.define(vec![name.clone()], DefKind::Local(*mutable), expr.span);
+        self.scopes.last_mut().unwrap().insert(name.clone(), id);
+        self.resolution.ids.insert(expr.span, id);
+      }
+      ExprKind::Assign(name, value) => {
+        self.resolve_expr(value);
+        match self.lookup_variable(name) {
+          Some(id) => {
+            if !matches!(self.resolution.def(id).kind, DefKind::Local(true)) {
+              self.errors.push(lang_error("Can't assign to an immutable variable, declare it with 'var'", expr.span));
+            }
+            self.resolution.ids.insert(expr.span, id);
+          }
+          None => self.errors.push(lang_error(&format!("Undefined variable '{}'", name.join("::")), expr.span)),
+        }
+      }
+      ExprKind::FuncDef(namespaced, params, _, stmts) => {
+        // definitions that weren't collected can't be called, but still get
+        // a definition for their parameters to belong to
+        let func = match self.resolution.resolve(expr) {
+          Some(func) => func,
+          None => {
+            let id = self.define(namespaced.clone(), DefKind::Function(vec![]), expr.span);
+            self.resolution.ids.insert(expr.span, id);
+            id
+          }
+        };
+        self.resolve_function(func, params, stmts);
+      }
+      ExprKind::FuncCall(name, args) => {
+        for arg in args {
+          self.resolve_expr(arg);
+        }
+        match self.functions.get(name) {
+          Some(&id) => { self.resolution.ids.insert(expr.span, id); }
+          None => self.errors.push(lang_error(&format!("Undefined function '{}'", name.join("::")), expr.span)),
+        }
+      }
+    }
+  }
+}
+
+// A type as the checker sees it, which may still be unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Ty {
+  Known(Type),
+  Var(usize),
+}
+
+struct TyVar {
+  binding: Option<Ty>,
+  integer: bool, // only an integer type will do, Int64 when nothing says which
+  function: Option<DefId>, // the function whose omitted return type this is
+}
+
+struct Checker<'a> {
+  resolution: &'a Resolution,
+  vars: Vec<TyVar>,
+  types: HashMap<Span, Ty>,
+  def_types: HashMap<DefId, Ty>,
+  literals: Vec<(Span, i128, Ty)>, // range checked once their types are known
+  ret_type: Option<Ty>, // return type of the function being checked, None for main
+  errors: Vec<LangError>,
+}
+
+impl<'a> Checker<'a> {
+  fn new_var(&mut self, integer: bool, function: Option<DefId>) -> Ty {
+    self.vars.push(TyVar { binding: None, integer, function });
+    Ty::Var(self.vars.len() - 1)
+  }
+
+  // Follows bound variables to what they stand for.
+  fn find(&self, ty: &Ty) -> Ty {
+    match ty {
+      Ty::Var(v) => match &self.vars[*v].binding {
+        Some(bound) => self.find(bound),
+        None => ty.clone(),
+      },
+      _ => ty.clone(),
+    }
+  }
+
+  fn describe(&self, ty: &Ty) -> String {
+    match self.find(ty) {
+      Ty::Known(ty) => ty.to_string(),
+      Ty::Var(v) if self.vars[v].integer => "an integer".to_string(),
+      Ty::Var(_) => "an unknown type".to_string(),
+    }
+  }
+
+  // Makes two types the same, failing when they're known to differ.
+  fn unify(&mut self, a: &Ty, b: &Ty) -> Result<(), ()> {
+    let (a, b) = (self.find(a), self.find(b));
+    match (&a, &b) {
+      _ if a == b => Ok(()),
+      (Ty::Var(v), Ty::Var(w)) => {
+        let (v, w) = (*v, *w);
+        self.vars[w].integer |= self.vars[v].integer;
+        self.vars[w].function = self.vars[w].function.or(self.vars[v].function);
+        self.vars[v].binding = Some(b);
+        Ok(())
+      }
+      (Ty::Var(v), Ty::Known(ty)) | (Ty::Known(ty), Ty::Var(v)) => {
+        if self.vars[*v].integer && !ty.is_integer() {
+          return Err(());
+        }
+        self.vars[*v].binding = Some(Ty::Known(*ty));
+        Ok(())
+      }
+      (Ty::Known(_), Ty::Known(_)) => Err(()),
+    }
+  }
+
+  // The type when it's known not to be an integer, otherwise it's required
+  // to be one from now on.
+  fn non_integer(&mut self, ty: &Ty) -> Option<Type> {
+    match self.find(ty) {
+      Ty::Known(ty) if !ty.is_integer() => Some(ty),
+      Ty::Var(v) => {
+        self.vars[v].integer = true;
+        None
+      }
+      _ => None,
+    }
+  }
+
+  // Values can be stored, passed and returned as a wider type that holds
+  // every value of theirs, e.g an Int32 as an Int64.
+  fn expect(&mut self, span: Span, actual: &Option<Ty>, ty: &Ty) {
+    if let Some(actual) = actual {
+      if !self.fits(actual, ty) {
+        let msg = format!("Expected {}, got {}", self.describe(ty), self.describe(actual));
+        self.errors.push(lang_error(&msg, span));
+      }
+    }
+  }
+
+  // Whether a value of type `actual` can be used as a `ty`, unifying the two
+  // when they're not known yet.
+  fn fits(&mut self, actual: &Ty, ty: &Ty) -> bool {
+    if let (Ty::Known(from), Ty::Known(to)) = (self.find(actual), self.find(ty)) {
+      if matches!((from, to), (Type::Int32, Type::Int64) | (Type::UInt32, Type::Int64) | (Type::UInt32, Type::UInt64)) {
+        return true;
+      }
+    }
+    self.unify(actual, ty).is_ok()
+  }
+
+  fn check_known(&mut self, ty: &str, span: Span) {
+    if Type::from_name(ty).is_none() {
+      self.errors.push(lang_error(&format!("Unknown type '{}'", ty), span));
+    }
+  }
+
+  // The type a name in the source stands for. `check_known` reports unknown
+  // names, which stand for any type so they don't cause more errors.
+  fn named(&mut self, ty: &str) -> Ty {
+    match Type::from_name(ty) {
+      Some(ty) => Ty::Known(ty),
+      None => self.new_var(false, None),
+    }
+  }
+
+  // Gives a function's return type and parameters their types, so calls can
+  // be checked before the definition is. An omitted return type is inferred
+  // from the body and every `return`.
+  fn declare_function(&mut self, expr: &Expr) {
+    if let ExprKind::FuncDef(_, params, ret, _) = &expr.kind {
+      let id = self.resolution.resolve(expr).unwrap();
+      if self.def_types.contains_key(&id) {
+        return;
+      }
+      let ret = match ret {
+        Some(ret) => self.named(ret),
+        None => self.new_var(false, Some(id)),
+      };
+      self.def_types.insert(id, ret);
+      if let DefKind::Function(ids) = &self.resolution.def(id).kind {
+        for (id, (_, ty, _)) in ids.iter().zip(params) {
+          let ty = self.named(ty);
+          self.def_types.insert(*id, ty);
+        }
+      }
+    }
+  }
+
+  fn declare_functions(&mut self, exprs: &[Expr]) {
+    for expr in exprs {
+      match &expr.kind {
+        ExprKind::FuncDef(_, _, _, stmts) => {
+          self.declare_function(expr);
+          self.declare_functions(stmts);
+        }
+        ExprKind::If(branches, otherwise) => {
+          for (_, stmts) in branches {
+            self.declare_functions(stmts);
+          }
+          if let Some(stmts) = otherwise {
+            self.declare_functions(stmts);
+          }
+        }
+        ExprKind::While(_, stmts) => self.declare_functions(stmts),
+        _ => {}
+      }
+    }
+  }
+
+  fn check_function(&mut self, expr: &Expr) {
+    if let ExprKind::FuncDef(_, params, ret, stmts) = &expr.kind {
+      self.declare_function(expr);
+      for (_, ty, span) in params {
+        self.check_known(ty, *span);
+      }
+      if let Some(ret) = ret {
+        self.check_known(ret, expr.span);
+      }
+
+      let ret = self.def_types[&self.resolution.resolve(expr).unwrap()].clone();
+      let outer = self.ret_type.replace(ret.clone());
+      let ty = self.check_block(stmts, true);
+      self.expect(stmts.last().map(|e| e.span).unwrap_or(expr.span), &ty, &ret);
+      self.ret_type = outer;
+    }
+  }
+
+  // The type of the last statement, or None when the block always breaks,
+  // continues or returns before reaching the end. `used` is whether the
+  // block's value is.
+  fn check_block(&mut self, stmts: &[Expr], used: bool) -> Option<Ty> {
+    let mut ty = Some(Ty::Known(Type::Int64)); // empty blocks are zero
+    let mut diverges = false;
+    for (i, stmt) in stmts.iter().enumerate() {
+      ty = self.check_expr(stmt, used && i + 1 == stmts.len());
+      diverges |= ty.is_none();
+    }
+    if diverges { None } else { ty }
+  }
+
+  fn check_expr(&mut self, expr: &Expr, used: bool) -> Option<Ty> {
+    let ty = self.infer_expr(expr, used);
+    self.types.insert(expr.span, ty.clone().unwrap_or(Ty::Known(Type::Int64)));
+    ty
+  }
+
+  fn infer_expr(&mut self, expr: &Expr, used: bool) -> Option<Ty> {
+    let span = expr.span;
+    match &expr.kind {
+      ExprKind::Number(num) => Some(self.literal(num, false, span)),
+      ExprKind::Bool(_) => Some(Ty::Known(Type::Bool)),
+      ExprKind::Symbol(_) => Some(self.def_types[&self.resolution.resolve(expr).unwrap()].clone()),
+      ExprKind::UnaryPrefix(op, rhs) if op == "not" => {
+        let ty = self.check_expr(rhs, true);
+        self.expect(rhs.span, &ty, &Ty::Known(Type::Bool));
+        Some(Ty::Known(Type::Bool))
+      }
+      // the sign is part of the literal, so each type's minimum can be written
+      ExprKind::UnaryPrefix(op, rhs) if op == "-" && matches!(rhs.kind, ExprKind::Number(_)) => {
+        let num = match &rhs.kind { ExprKind::Number(num) => num, _ => unreachable!() };
+        let ty = self.literal(num, true, span);
+        self.types.insert(rhs.span, ty.clone());
+        Some(ty)
+      }
+      ExprKind::UnaryPrefix(op, rhs) => {
+        let ty = self.check_expr(rhs, true);
+        if let Some(name) = ty.as_ref().and_then(|ty| self.non_integer(ty)) {
+          self.errors.push(lang_error(&format!("Operator '{}' expects an integer, got {}", op, name), span));
+        }
+        ty
+      }
+      ExprKind::BinaryInfix(lhs, op, rhs) if op == "and" || op == "or" => {
+        for operand in [lhs, rhs] {
+          let ty = self.check_expr(operand, true);
+          self.expect(operand.span, &ty, &Ty::Known(Type::Bool));
+        }
+        Some(Ty::Known(Type::Bool))
+      }
+      ExprKind::BinaryInfix(lhs, op, rhs) => {
+        let (lty, rty) = (self.check_expr(lhs, true), self.check_expr(rhs, true));
+        let ty = match (lty, rty) {
+          (Some(lty), Some(rty)) => {
+            if self.unify(&lty, &rty).is_err() {
+              let msg = format!("Operator '{}' expects operands of the same type, got {} and {}",
+                op, self.describe(&lty), self.describe(&rty));
+              self.errors.push(lang_error(&msg, span));
+            }
+            Some(lty)
+          }
+          (lty, rty) => lty.or(rty),
+        };
+        if op != "==" && op != "!=" {
+          if let Some(name) = ty.as_ref().and_then(|ty| self.non_integer(ty)) {
+            self.errors.push(lang_error(&format!("Operator '{}' expects integers, got {}", op, name), span));
+          }
+        }
+        if matches!(op.as_str(), "==" | "!=" | "<" | "<=" | ">" | ">=") { Some(Ty::Known(Type::Bool)) } else { ty }
+      }
+      ExprKind::If(branches, otherwise) => {
+        // the first branch to produce a value decides the type, the others
+        // have to agree with it when the value is used
+        let mut result: Option<Ty> = None;
+        let mut all_diverge = true;
+        let blocks = branches.iter().map(|(cond, stmts)| (Some(cond), stmts.as_slice()))
+          .chain(otherwise.iter().map(|stmts| (None, stmts.as_slice())));
+        for (cond, stmts) in blocks {
+          if let Some(cond) = cond {
+            let ty = self.check_expr(cond, true);
+            self.expect(cond.span, &ty, &Ty::Known(Type::Bool));
+          }
+          let ty = match self.check_block(stmts, used) {
+            Some(ty) => ty,
+            None => continue,
+          };
+          all_diverge = false;
+          match &result {
+            None => result = Some(ty),
+            Some(first) if used && self.unify(first, &ty).is_err() => {
+              let msg = format!("Expected {} like the first branch, got {}", self.describe(first), self.describe(&ty));
+              self.errors.push(lang_error(&msg, stmts.last().map(|e| e.span).unwrap_or(span)));
+            }
+            Some(_) => {}
+          }
+        }
+        // without an else, the value is zero when no branch is taken
+        if all_diverge && otherwise.is_some() {
+          None
+        } else {
+          Some(result.unwrap_or(Ty::Known(Type::Int64)))
+        }
+      }
+      ExprKind::While(cond, stmts) => {
+        let ty = self.check_expr(cond, true);
+        self.expect(cond.span, &ty, &Ty::Known(Type::Bool));
+        self.check_block(stmts, false);
+        Some(Ty::Known(Type::Int64))
+      }
+      ExprKind::Break | ExprKind::Continue => None,
+      ExprKind::Return(value) => {
+        match (value, self.ret_type.clone()) {
+          (Some(value), Some(ret)) => {
+            let ty = self.check_expr(value, true);
+            self.expect(value.span, &ty, &ret);
+          }
+          // main's value is converted to its Int64, whatever the type
+          (Some(value), None) => {
+            self.check_expr(value, true);
+          }
+          // returning nothing returns an Int64 zero
+          (None, Some(ret)) => match self.find(&ret) {
+            Ty::Known(ty) if ty != Type::Int64 => {
+              self.errors.push(lang_error(&format!("Expected a return value of type {}", ty), span));
+            }
+            _ => self.expect(span, &Some(Ty::Known(Type::Int64)), &ret),
+          },
+          (None, None) => {}
+        }
+        None
+      }
+      ExprKind::Let(_, ntype, _, value) => {
+        let vty = self.check_expr(value, true);
+        let ty = match ntype {
+          Some(ntype) => {
+            self.check_known(ntype, span);
+            let ty = self.named(ntype);
+            self.expect(value.span, &vty, &ty);
+            ty
+          }
+          None => vty.unwrap_or(Ty::Known(Type::Int64)),
+        };
+        self.def_types.insert(self.resolution.resolve(expr).unwrap(), ty.clone());
+        Some(ty)
+      }
+      ExprKind::Assign(_, value) => {
+        let var_ty = self.def_types[&self.resolution.resolve(expr).unwrap()].clone();
+        let ty = self.check_expr(value, true);
+        self.expect(value.span, &ty, &var_ty);
+        Some(var_ty)
+      }
+      ExprKind::FuncDef(..) => {
+        self.check_function(expr);
+        Some(Ty::Known(Type::Int64))
+      }
+      ExprKind::FuncCall(_, args) => {
+        let func = self.resolution.resolve(expr).unwrap();
+        let def = self.resolution.def(func);
+        let (name, def_span) = (def.name.join("::"), def.span);
+        let params = match &def.kind {
+          DefKind::Function(params) => params.clone(),
+          _ => unreachable!(),
+        };
+        // errors point at the call, with a note pointing at the definition
+        let defined_here = || vec![lang_note(&format!("'{}' is defined here", name), def_span)];
+        let arg_types: Vec<Option<Ty>> = args.iter().map(|arg| self.check_expr(arg, true)).collect();
+        if params.len() != args.len() {
+          let msg = format!("'{}' expects {} argument(s), got {}", name, params.len(), args.len());
+          self.errors.push(lang_error_noted(&msg, span, defined_here()));
+        } else {
+          for (i, (ty, param)) in arg_types.iter().zip(&params).enumerate() {
+            let param_ty = self.def_types[param].clone();
+            if let Some(ty) = ty {
+              if !self.fits(ty, &param_ty) {
+                let msg = format!(
+                  "Argument {} of '{}' expects {}, got {}",
+                  i + 1, name, self.describe(&param_ty), self.describe(ty)
+                );
+                self.errors.push(lang_error_noted(&msg, span, defined_here()));
+              }
+            }
+          }
+        }
+        Some(self.def_types[&func].clone())
+      }
+    }
+  }
+
+  // Literals take whichever integer type they're used as, so their range is
+  // checked once that's known.
+  fn literal(&mut self, num: &str, negated: bool, span: Span) -> Ty {
+    let ty = self.new_var(true, None);
+    if num.contains('.') {
+      self.errors.push(lang_error("Floating point literals aren't supported", span));
+      return ty;
+    }
+    match num.parse::<u64>() {
+      Ok(n) => self.literals.push((span, if negated { -(n as i128) } else { n as i128 }, ty.clone())),
+      Err(_) => self.errors.push(lang_error("Integer literal is too large", span)),
+    }
+    ty
+  }
+
+  // Settles every type still unknown once the whole program has been seen:
+  // integers default to Int64, a return type nothing decides is ambiguous.
+  fn finish(&mut self) {
+    for v in 0..self.vars.len() {
+      if let Ty::Var(root) = self.find(&Ty::Var(v)) {
+        if !self.vars[root].integer {
+          if let Some(func) = self.vars[root].function {
+            let def = self.resolution.def(func);
+            let msg = format!("Can't infer the return type of '{}', declare it with '-> Type'", def.name.join("::"));
+            self.errors.push(lang_error(&msg, def.span));
+          }
+        }
+        self.vars[root].binding = Some(Ty::Known(Type::Int64));
+      }
+    }
+
+    for (span, n, ty) in std::mem::take(&mut self.literals) {
+      if let Ty::Known(ty) = self.find(&ty) {
+        let (min, max) = match ty {
+          Type::Int32 => (i32::MIN as i128, i32::MAX as i128),
+          Type::Int64 => (i64::MIN as i128, i64::MAX as i128),
+          Type::UInt32 => (0, u32::MAX as i128),
+          Type::UInt64 => (0, u64::MAX as i128),
+          Type::Bool => continue,
+        };
+        if n > max {
+          self.errors.push(lang_error(&format!("Integer literal is too large for {}", ty), span));
+        } else if n < min {
+          self.errors.push(lang_error(&format!("Integer literal is too small for {}", ty), span));
+        }
+      }
+    }
+  }
+
+  fn known_type(&self, ty: &Ty) -> Type {
+    match self.find(ty) {
+      Ty::Known(ty) => ty,
+      Ty::Var(_) => unreachable!("every type is known once checking finishes"),
+    }
+  }
+}
+
+// Names and types of a program, as `analyse` found them.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+  pub resolution: Resolution,
+  types: HashMap<Span, Type>,
+  def_types: HashMap<DefId, Type>,
+}
+
+impl Analysis {
+  // The type of an expression's value, Int64 for those that never finish.
+  pub fn type_of(&self, expr: &Expr) -> Type {
+    self.types[&expr.span]
+  }
+
+  // The type of a variable or parameter, or a function's return type.
+  pub fn def_type(&self, id: DefId) -> Type {
+    self.def_types[&id]
+  }
+
+  // Writes the inferred type of every `let` and return type of every
+  // function that omits one into the program.
+  pub fn annotate(&self, exprs: &mut [Expr]) {
+    for expr in exprs {
+      self.annotate_expr(expr);
+    }
+  }
+
+  fn annotate_expr(&self, expr: &mut Expr) {
+    let id = self.resolution.resolve(expr);
+    match &mut expr.kind {
+      ExprKind::Number(_) | ExprKind::Bool(_) | ExprKind::Symbol(_) | ExprKind::Break | ExprKind::Continue => {}
+      ExprKind::UnaryPrefix(_, rhs) => self.annotate_expr(rhs),
+      ExprKind::BinaryInfix(lhs, _, rhs) => {
+        self.annotate_expr(lhs);
+        self.annotate_expr(rhs);
+      }
+      ExprKind::If(branches, otherwise) => {
+        for (cond, stmts) in branches {
+          self.annotate_expr(cond);
+          self.annotate(stmts);
+        }
+        if let Some(stmts) = otherwise {
+          self.annotate(stmts);
+        }
+      }
+      ExprKind::While(cond, stmts) => {
+        self.annotate_expr(cond);
+        self.annotate(stmts);
+      }
+      ExprKind::Return(value) => {
+        if let Some(value) = value {
+          self.annotate_expr(value);
+        }
+      }
+      ExprKind::Let(_, ntype, _, value) => {
+        ntype.get_or_insert_with(|| self.def_type(id.unwrap()).name().to_string());
+        self.annotate_expr(value);
+      }
+      ExprKind::Assign(_, value) => self.annotate_expr(value),
+      ExprKind::FuncDef(_, _, ret, stmts) => {
+        ret.get_or_insert_with(|| self.def_type(id.unwrap()).name().to_string());
+        self.annotate(stmts);
+      }
+      ExprKind::FuncCall(_, args) => self.annotate(args),
+    }
+  }
+}
+
+fn into_result(mut errors: Vec<LangError>) -> IResult<()> {
+  match errors.len() {
+    0 => Ok(()),
+    1 => Err(errors.pop().unwrap()),
+    _ => {
+      errors.sort_by_key(|e| e.span.start);
+      let start = errors.first().unwrap().span.start;
+      let end = errors.last().unwrap().span.end;
+      Err(lang_errors(span(start, end), errors))
+    }
+  }
+}
+
+// Resolves every name in a parsed program to its definition, then infers,
+// checks and records the type of every expression, before codegen.
+pub fn analyse(src: &str, exprs: &[Expr]) -> IResult<Analysis> {
+  analyse_after(src, exprs, 0)
 }
 
+// Like `analyse`, for a program whose first `settled` statements were
+// accepted before, as the REPL's earlier inputs were. Their types are settled
+// before the rest is checked, so later statements can't change the types
+// code was already generated for.
+pub fn analyse_after(src: &str, exprs: &[Expr], settled: usize) -> IResult<Analysis> {
+  let mut resolver = Resolver {
+    resolution: Resolution { defs: vec![], main: DefId(0), ids: HashMap::new() },
+    functions: HashMap::new(),
+    scopes: vec![],
+    loops: 0,
+    errors: vec![],
+  };
+  resolver.collect_functions(exprs);
+  let main = resolver.define(vec!["main".to_string()], DefKind::Function(vec![]), span(0, src.len()));
+  resolver.resolution.main = main;
+  resolver.resolve_function(main, &[], exprs);
+  into_result(resolver.errors)?;
+  let resolution = resolver.resolution;
+
+  let mut checker = Checker {
+    resolution: &resolution,
+    vars: vec![],
+    types: HashMap::new(),
+    def_types: HashMap::new(),
+    literals: vec![],
+    ret_type: None,
+    errors: vec![],
+  };
+  checker.def_types.insert(main, Ty::Known(Type::Int64));
+  let (earlier, later) = exprs.split_at(settled);
+  for stmts in [earlier, later] {
+    checker.declare_functions(stmts);
+    checker.check_block(stmts, true);
+    checker.finish();
+  }
+  into_result(std::mem::take(&mut checker.errors))?;
+
+  let types = checker.types.iter().map(|(span, ty)| (*span, checker.known_type(ty))).collect();
+  let def_types = checker.def_types.iter().map(|(id, ty)| (*id, checker.known_type(ty))).collect();
+  Ok(Analysis { resolution, types, def_types })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{analyse, DefKind};
+  use crate::lang::error::{report_error, LangError, LangErrorKind};
+  use crate::lang::hir::Type;
+  use crate::lang::parse::{parse, ExprKind};
+
+  fn first_lines(src: &str, err: LangError) -> Vec<String> {
+    let errors = match err.kind {
+      LangErrorKind::Many(errors) => errors,
+      _ => vec![err],
+    };
+    errors.into_iter().map(|e| report_error(src, e).lines().next().unwrap().to_string()).collect()
+  }
+
+  #[test]
+  fn shadowed_bindings_get_their_own_definitions() {
+    let src = "def f(a Int64) -> Int64\n  a\nend\nlet x = 1\nlet x = x + f(2)\nx\n";
+    let program = parse(src).unwrap();
+    let resolution = analyse(src, &program).unwrap().resolution;
+
+    let (first, second) = (resolution.resolve(&program[1]).unwrap(), resolution.resolve(&program[2]).unwrap());
+    assert_ne!(first, second);
+    assert_eq!(resolution.resolve(&program[3]), Some(second));
+    match &program[2].kind {
+      ExprKind::Let(_, _, _, value) => match &value.kind {
+        ExprKind::BinaryInfix(lhs, _, rhs) => {
+          assert_eq!(resolution.resolve(lhs), Some(first));
+          assert_eq!(resolution.resolve(rhs), resolution.resolve(&program[0]));
+        }
+        _ => unreachable!(),
+      },
+      _ => unreachable!(),
+    }
+
+    let f = resolution.resolve(&program[0]).unwrap();
+    match &resolution.def(f).kind {
+      DefKind::Function(params) => assert_eq!(params.len(), 1),
+      _ => unreachable!(),
+    }
+  }
+
+  #[test]
+  fn undefined_and_duplicate_names_are_all_reported() {
+    let src = "def f() -> Int32\n  y\nend\ndef f() -> Int32\n  1\nend\nlet x = x\ng(1)\n";
+    let err = analyse(src, &parse(src).unwrap()).unwrap_err();
+    assert_eq!(first_lines(src, err), [
+      "2:3: error: Undefined variable 'y'",
+      "4:1: error: Function 'f' is already defined",
+      "7:9: error: Undefined variable 'x'",
+      "8:1: error: Undefined function 'g'",
+    ]);
+  }
+
+  #[test]
+  fn duplicate_functions_point_at_the_first_definition() {
+    let src = "def math::f() -> Int32\n  1\nend\ndef math::f() -> Int32\n  2\nend\nmath::f()\n";
+    let rendered = crate::Session::new(src).check().unwrap_err().render(src);
+    let lines: Vec<&str> = rendered.lines().filter(|l| !l.starts_with('\t')).collect();
+    assert_eq!(lines, ["4:1: error: Function 'math::f' is already defined", "1:1: note: 'math::f' is first defined here"]);
+  }
+
+  #[test]
+  fn parameters_are_defined_where_they_are_written() {
+    let src = "def f(a Int32, b Int32, a Bool) -> Int32\n  a\nend\n";
+    let err = analyse(src, &parse(src).unwrap()).unwrap_err();
+    assert_eq!(first_lines(src, err), ["1:25: error: Parameter 'a' is already defined"]);
+
+    let src = "def f(a Int32, b Int32) -> Int32\n  a\nend\n";
+    let program = parse(src).unwrap();
+    let resolution = analyse(src, &program).unwrap().resolution;
+    let params = match &resolution.def(resolution.resolve(&program[0]).unwrap()).kind {
+      DefKind::Function(params) => params.clone(),
+      _ => unreachable!(),
+    };
+    let spans: Vec<&str> = params.iter().map(|&p| &src[resolution.def(p).span.start..resolution.def(p).span.end]).collect();
+    assert_eq!(spans, ["a Int32", "b Int32"]);
+  }
+
+  #[test]
+  fn bad_calls_point_at_the_definition() {
+    let src = "def f(a Int32, b Bool) -> Int32\n  a\nend\nf(1)\nf(true, false)\n";
+    let rendered = crate::Session::new(src).check().unwrap_err().render(src);
+    let lines: Vec<&str> = rendered.lines().filter(|l| !l.starts_with('\t')).collect();
+    assert_eq!(lines, [
+      "4:1: error: 'f' expects 2 argument(s), got 1",
+      "1:1: note: 'f' is defined here",
+      "5:1: error: Argument 1 of 'f' expects Int32, got Bool",
+      "1:1: note: 'f' is defined here",
+    ]);
+  }
+
+  #[test]
+  fn every_expression_is_annotated_with_a_type() {
+    let src = "def f(a Int32, b UInt32) -> Int64
+  a * 2
+end
+let x = f(1, 2) + 1
+x < 3 or false
+";
+    let program = parse(src).unwrap();
+    let analysis = analyse(src, &program).unwrap();
+
+    let body = match &program[0].kind {
+      ExprKind::FuncDef(_, _, _, stmts) => &stmts[0],
+      _ => unreachable!(),
+    };
+    match &body.kind {
+      ExprKind::BinaryInfix(lhs, _, rhs) => {
+        // the literal takes the type of the other operand
+        assert_eq!(analysis.type_of(lhs), Type::Int32);
+        assert_eq!(analysis.type_of(rhs), Type::Int32);
+      }
+      _ => unreachable!(),
+    }
+    assert_eq!(analysis.type_of(&program[1]), Type::Int64);
+    assert_eq!(analysis.type_of(&program[2]), Type::Bool);
+  }
+
+  #[test]
+  fn type_mismatches_are_reported() {
+    let src = "\
+def f(a Int32) -> Int32
+  a
+end
+let x Int64 = 1
+let y Int32 = x
+f(x) + x
+if x
+  true
+else
+  1
+end
+let z Bool = 300
+let w UInt32 = 4294967296
+";
+    let err = analyse(src, &parse(src).unwrap()).unwrap_err();
+    assert_eq!(first_lines(src, err), [
+      "5:15: error: Expected Int32, got Int64",
+      "6:1: error: Argument 1 of 'f' expects Int32, got Int64",
+      "6:1: error: Operator '+' expects operands of the same type, got Int32 and Int64",
+      "7:4: error: Expected Bool, got Int64",
+      "12:14: error: Expected Bool, got an integer",
+      "13:16: error: Integer literal is too large for UInt32",
+    ]);
+  }
+
+  #[test]
+  fn literals_fit_their_type_with_the_sign_folded_in() {
+    let src = "\
+let a Int32 = -2147483648
+let b Int64 = -9223372036854775808
+let c UInt64 = 18446744073709551615
+let d Int32 = -2147483649
+let e UInt32 = -1
+let f Int64 = 9223372036854775808
+let g = 18446744073709551616
+let h = 1.5
+a + 1
+";
+    let err = analyse(src, &parse(src).unwrap()).unwrap_err();
+    assert_eq!(first_lines(src, err), [
+      "4:15: error: Integer literal is too small for Int32",
+      "5:16: error: Integer literal is too small for UInt32",
+      "6:15: error: Integer literal is too large for Int64",
+      "7:9: error: Integer literal is too large",
+      "8:9: error: Floating point literals aren't supported",
+    ]);
+  }
+
+  #[test]
+  fn omitted_types_are_inferred() {
+    let src = "\
+def even(n UInt32)
+  if n == 0
+    true
+  else
+    odd(n - 1)
+  end
+end
+def odd(n UInt32)
+  if n == 0
+    return false
+  end
+  even(n - 1)
+end
+def half(n Int32)
+  let m = n / 2
+  m
+end
+let x = half(7)
+even(4)
+";
+    let mut program = parse(src).unwrap();
+    let analysis = analyse(src, &program).unwrap();
+    analysis.annotate(&mut program);
+
+    let rets: Vec<_> = program[..3].iter().map(|def| match &def.kind {
+      ExprKind::FuncDef(_, _, ret, _) => ret.clone().unwrap(),
+      _ => unreachable!(),
+    }).collect();
+    assert_eq!(rets, ["Bool", "Bool", "Int32"]);
+    match &program[3].kind {
+      ExprKind::Let(_, ntype, _, _) => assert_eq!(ntype.as_deref(), Some("Int32")),
+      _ => unreachable!(),
+    }
+  }
+
+  #[test]
+  fn ambiguous_return_types_are_reported() {
+    let src = "def f(n Int64)\n  f(n)\nend\nf(1)\n";
+    let err = analyse(src, &parse(src).unwrap()).unwrap_err();
+    assert_eq!(first_lines(src, err), [
+      "1:1: error: Can't infer the return type of 'f', declare it with '-> Type'",
+    ]);
+  }
+}
@@ -1,6 +1,4 @@
-use std::io::BufRead;
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Span {
   pub start: usize,
   pub end: usize,
@@ -17,8 +15,10 @@ pub fn span_single(startend: usize) -> Span {
 #[derive(Debug, Clone)]
 pub enum LangErrorKind {
   Many(Vec<LangError>),
-  Contextual(Vec<LangError>),
+  Contextual(Vec<LangError>), // notes reported after the error, e.g where a function is defined
+  Note,
   Fatal,
+  Global, // not tied to any source location, e.g a bad target triple
 }
 
 #[derive(Debug, Clone)]
@@ -34,6 +34,14 @@ pub fn lang_error(msg: &str, span: Span) -> LangError {
   LangError { msg: msg.to_string(), span, kind: LangErrorKind::Contextual(vec![]) }
 }
 
+pub fn lang_error_noted(msg: &str, span: Span, notes: Vec<LangError>) -> LangError {
+  LangError { msg: msg.to_string(), span, kind: LangErrorKind::Contextual(notes) }
+}
+
+pub fn lang_note(msg: &str, span: Span) -> LangError {
+  LangError { msg: msg.to_string(), span, kind: LangErrorKind::Note }
+}
+
 pub fn lang_errors(span: Span, errors: Vec<LangError>) -> LangError {
   LangError { msg: String::new(), span, kind: LangErrorKind::Many(errors) }
 }
@@ -42,6 +50,10 @@ pub fn lang_error_fatal(msg: &str, span: Span) -> LangError {
   LangError { msg: msg.to_string(), span, kind: LangErrorKind::Fatal }
 }
 
+pub fn lang_error_global(msg: &str) -> LangError {
+  LangError { msg: msg.to_string(), span: span_single(0), kind: LangErrorKind::Global }
+}
+
 pub fn report_error(src: &str, err: LangError) -> String {
 
   use std::fmt::Write;
@@ -50,10 +62,14 @@ pub fn report_error(src: &str, err: LangError) -> String {
   
   if let LangErrorKind::Many(e) = err.kind.clone() {
     for me in e {
-      write!(&mut buf, "{}\n", report_error(src, me)).unwrap();
+      writeln!(&mut buf, "{}", report_error(src, me)).unwrap();
     }
     return buf;
   }
+  
+  if let LangErrorKind::Global = err.kind {
+    return format!("error: {}", err.msg);
+  }
 
   let prefix = &src.as_bytes()[..err.span.start];
   let line_number = prefix.iter().filter(|&&c| c == b'\n').count() + 1;
@@ -69,16 +85,20 @@ pub fn report_error(src: &str, err: LangError) -> String {
   let column = err.span.start - line_begin;
 
 
-  write!(&mut buf, "{}:{}: error: {}\n", 
+  let label = if let LangErrorKind::Note = err.kind { "note" } else { "error" };
+  writeln!(&mut buf, "{}:{}: {}: {}", 
     line_number,
     column+1,
+    label,
     err.msg,
   ).unwrap();
-  write!(&mut buf, "\t{}\n", line).unwrap();
+  writeln!(&mut buf, "\t{}", line).unwrap();
   if err.span.start == err.span.end {
     write!(&mut buf, "\t{}^", " ".repeat(column)).unwrap();
   } else {
-    write!(&mut buf, "\t{}{}", " ".repeat(column), "~".repeat(err.span.end - err.span.start)).unwrap();
+    // spans over several lines are only underlined up to the end of the first
+    let width = (err.span.end - err.span.start).min(line.len().saturating_sub(column)).max(1);
+    write!(&mut buf, "\t{}{}", " ".repeat(column), "~".repeat(width)).unwrap();
   }
 
   buf
@@ -0,0 +1,610 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use super::{
+  analyse::{Analysis, DefId, DefKind},
+  error::{lang_error_fatal, report_error, LangError, Span},
+  parse::{self, ExprKind as Ast},
+};
+
+// The program as every backend sees it, lowered from the analysed AST. Names
+// are resolved to the function or local they refer to, every expression has
+// its type, implicit conversions are explicit casts, and `elsif`, `and`,
+// `or`, bare returns and ifs without an else are desugared.
+#[derive(Debug, Clone)]
+pub struct Program {
+  pub functions: Vec<Function>, // in source order, main last
+  pub globals: Vec<Global>, // only programs from `lower_after` have any
+}
+
+impl Program {
+  pub fn main(&self) -> &Function {
+    self.functions.last().unwrap()
+  }
+}
+
+// Indexes `Program::functions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FuncId(pub usize);
+
+// Indexes `Function::locals`, numbered from zero in every function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LocalId(pub usize);
+
+// Indexes `Program::globals`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalId(pub usize);
+
+// Nested definitions are hoisted out of the bodies they're written in, and
+// the top level of the program becomes `main`, which takes nothing and
+// returns an Int64.
+#[derive(Debug, Clone)]
+pub struct Function {
+  pub id: FuncId,
+  pub name: Vec<String>,
+  pub params: usize, // the first locals are the parameters
+  pub locals: Vec<Local>,
+  pub ret: Type,
+  pub body: Vec<Expr>, // the last expression is the value returned
+  pub span: Span,
+}
+
+impl Function {
+  pub fn is_main(&self) -> bool {
+    self.name.len() == 1 && self.name[0] == "main"
+  }
+
+  pub fn params(&self) -> &[Local] {
+    &self.locals[..self.params]
+  }
+}
+
+// Every `let` defines a local of its own, so shadowing a name leaves the
+// previous binding untouched; backends give each local its own variable.
+// Functions' symbols are `sfd_` followed by their namespaces joined with
+// `_N`, so they can't clash with the C library's. Underscores are doubled, so
+// the mangled name can't be confused with another function's.
+pub fn mangle(name: &[String]) -> String {
+  let segments: Vec<String> = name.iter().map(|s| escape_identifier(s)).collect();
+  format!("sfd_{}", segments.join("_N"))
+}
+
+// Keeps identifiers valid in C and in object files, e.g `digit?` becomes
+// `digit_Q`.
+pub fn escape_identifier(name: &str) -> String {
+  let mut escaped = String::new();
+  for c in name.chars() {
+    match c {
+      '_' => escaped.push_str("__"),
+      '?' => escaped.push_str("_Q"),
+      c if c.is_ascii_alphanumeric() => escaped.push(c),
+      c => write!(escaped, "_U{:x}_", c as u32).unwrap(),
+    }
+  }
+  escaped
+}
+
+#[derive(Debug, Clone)]
+pub struct Local {
+  pub name: String,
+  pub ty: Type,
+  pub mutable: bool,
+}
+
+// A binding at the top level that outlives the program, for the REPL's later
+// inputs to use. The `let` defining it identifies it across programs.
+#[derive(Debug, Clone)]
+pub struct Global {
+  pub name: String,
+  pub ty: Type,
+  pub span: Span, // of the `let`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+  Int32,
+  Int64,
+  UInt32,
+  UInt64,
+  Bool,
+}
+
+impl Type {
+  pub fn from_name(name: &str) -> Option<Type> {
+    match name {
+      "Int32" => Some(Type::Int32),
+      "Int64" => Some(Type::Int64),
+      "UInt32" => Some(Type::UInt32),
+      "UInt64" => Some(Type::UInt64),
+      "Bool" => Some(Type::Bool),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Type::Int32 => "Int32",
+      Type::Int64 => "Int64",
+      Type::UInt32 => "UInt32",
+      Type::UInt64 => "UInt64",
+      Type::Bool => "Bool",
+    }
+  }
+
+  // Bools are held in 8 bits, as 0 or 1.
+  pub fn bits(self) -> u32 {
+    match self {
+      Type::Int32 | Type::UInt32 => 32,
+      Type::Bool => 8,
+      Type::Int64 | Type::UInt64 => 64,
+    }
+  }
+
+  pub fn is_unsigned(self) -> bool {
+    matches!(self, Type::UInt32 | Type::UInt64)
+  }
+
+  pub fn is_integer(self) -> bool {
+    self != Type::Bool
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+  Not,
+  Neg,
+  BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+  Add,
+  Sub,
+  Mul,
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+impl BinaryOp {
+  fn from_symbol(op: &str) -> BinaryOp {
+    match op {
+      "+" => BinaryOp::Add,
+      "-" => BinaryOp::Sub,
+      "*" => BinaryOp::Mul,
+      "==" => BinaryOp::Eq,
+      "!=" => BinaryOp::Ne,
+      "<" => BinaryOp::Lt,
+      "<=" => BinaryOp::Le,
+      ">" => BinaryOp::Gt,
+      ">=" => BinaryOp::Ge,
+      _ => unreachable!("the parser only produces known operators"),
+    }
+  }
+
+  pub fn symbol(self) -> &'static str {
+    match self {
+      BinaryOp::Add => "+",
+      BinaryOp::Sub => "-",
+      BinaryOp::Mul => "*",
+      BinaryOp::Eq => "==",
+      BinaryOp::Ne => "!=",
+      BinaryOp::Lt => "<",
+      BinaryOp::Le => "<=",
+      BinaryOp::Gt => ">",
+      BinaryOp::Ge => ">=",
+    }
+  }
+
+  pub fn is_comparison(self) -> bool {
+    !matches!(self, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivOp {
+  Div,
+  Rem,
+}
+
+impl DivOp {
+  pub fn symbol(self) -> &'static str {
+    match self {
+      DivOp::Div => "/",
+      DivOp::Rem => "%",
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+  Int(i64), // a literal, with a leading minus folded in
+  Bool(bool),
+  Local(LocalId),
+
+  Unary(UnaryOp, Box<Expr>),
+  Binary(Box<Expr>, BinaryOp, Box<Expr>), // both operands have the same type
+  // like Binary, with what to report when the divisor is zero and, for
+  // signed operands, when the quotient overflows, as MIN / -1 and MIN % -1
+  // do. Neither is left to the hardware: backends check the operands so the
+  // program can report where it happened before aborting
+  Divide(Box<Expr>, DivOp, Box<Expr>, Trap, Option<Trap>),
+  // truncates, or sign or zero extends depending on the operand's type
+  Cast(Box<Expr>),
+
+  If(Box<Expr>, Vec<Expr>, Vec<Expr>), // condition, then, else
+  While(Box<Expr>, Vec<Expr>), // condition, stmts
+  Break,
+  Continue,
+  Return(Box<Expr>), // already of the function's return type
+
+  Let(LocalId, Box<Expr>),
+  Assign(LocalId, Box<Expr>),
+  Global(GlobalId),
+  SetGlobal(GlobalId, Box<Expr>), // defines or assigns it
+
+  Call(FuncId, Vec<Expr>), // arguments already of the parameters' types
+}
+
+// A runtime error, rendered against the source while lowering so backends
+// don't need the source to report it.
+#[derive(Debug, Clone)]
+pub struct Trap {
+  pub error: LangError,
+  pub report: String, // as printed to stderr, newline included
+}
+
+// Blocks are never empty, and the value of a block is its last expression's.
+// Break, continue and return are Int64 zeros, as far as their type goes.
+// Whatever follows them in a block is dead, but still has to be translated.
+#[derive(Debug, Clone)]
+pub struct Expr {
+  pub kind: ExprKind,
+  pub ty: Type,
+  pub span: Span,
+}
+
+impl Expr {
+  fn new(kind: ExprKind, ty: Type, span: Span) -> Self {
+    Expr { kind, ty, span }
+  }
+
+  fn zero(ty: Type, span: Span) -> Self {
+    let kind = if ty == Type::Bool { ExprKind::Bool(false) } else { ExprKind::Int(0) };
+    Expr::new(kind, ty, span)
+  }
+
+  fn cast(self, ty: Type) -> Self {
+    if self.ty == ty {
+      return self;
+    }
+    let span = self.span;
+    Expr::new(ExprKind::Cast(Box::new(self)), ty, span)
+  }
+}
+
+struct Lowerer<'a> {
+  src: &'a str,
+  analysis: &'a Analysis,
+  functions: HashMap<DefId, FuncId>,
+  locals: Vec<Local>, // of the function being lowered
+  local_ids: HashMap<DefId, LocalId>,
+  ret: Type,
+  globals: Vec<Global>,
+  global_ids: HashMap<DefId, GlobalId>,
+  top_level: bool, // whether a `let` here defines a global
+}
+
+// The literal's bits, UInt64s above i64::MAX wrap around.
+fn literal(num: &str) -> i64 {
+  num.parse::<u64>().expect("analysis checks literals fit") as i64
+}
+
+impl<'a> Lowerer<'a> {
+  fn def_type(&self, id: DefId) -> Type {
+    self.analysis.def_type(id)
+  }
+
+  fn resolve(&self, expr: &parse::Expr) -> DefId {
+    self.analysis.resolution.resolve(expr).expect("analysis resolves every name")
+  }
+
+  fn function(&mut self, id: FuncId, name: Vec<String>, params: &[DefId], ret: Type, stmts: &[parse::Expr], span: Span) -> Function {
+    self.locals.clear();
+    self.local_ids.clear();
+    self.ret = ret;
+    for &param in params {
+      self.define(param, false);
+    }
+    let body = self.lower_block(stmts, ret, span);
+    let locals = std::mem::take(&mut self.locals);
+    Function { id, name, params: params.len(), locals, ret, body, span }
+  }
+
+  fn define(&mut self, def: DefId, mutable: bool) -> LocalId {
+    let id = LocalId(self.locals.len());
+    let name = self.analysis.resolution.def(def).name.join("::");
+    self.locals.push(Local { name, ty: self.def_type(def), mutable });
+    self.local_ids.insert(def, id);
+    id
+  }
+
+  fn trap(&self, msg: &str, span: Span) -> Trap {
+    let error = lang_error_fatal(msg, span);
+    let report = report_error(self.src, error.clone()) + "\n";
+    Trap { error, report }
+  }
+
+  // Bindings from the REPL's earlier inputs are the only ones that aren't
+  // locals, see `lower_after`.
+  fn variable(&mut self, def: DefId) -> Result<LocalId, GlobalId> {
+    match self.local_ids.get(&def) {
+      Some(&local) => Ok(local),
+      None => Err(self.global(def)),
+    }
+  }
+
+  fn global(&mut self, def: DefId) -> GlobalId {
+    if let Some(&id) = self.global_ids.get(&def) {
+      return id;
+    }
+    let id = GlobalId(self.globals.len());
+    let name = self.analysis.resolution.def(def).name.join("::");
+    let span = self.analysis.resolution.def(def).span;
+    self.globals.push(Global { name, ty: self.def_type(def), span });
+    self.global_ids.insert(def, id);
+    id
+  }
+
+  // Lowers a block inside another, where bindings are always locals.
+  fn nested<T>(&mut self, lower: impl FnOnce(&mut Self) -> T) -> T {
+    let top_level = std::mem::replace(&mut self.top_level, false);
+    let lowered = lower(self);
+    self.top_level = top_level;
+    lowered
+  }
+
+  // Lowers the statements of a block whose value is used as a `ty`.
+  fn lower_block(&mut self, stmts: &[parse::Expr], ty: Type, span: Span) -> Vec<Expr> {
+    let mut block = self.lower_stmts(stmts, span);
+    let last = block.pop().unwrap().cast(ty);
+    block.push(last);
+    block
+  }
+
+  fn lower_stmts(&mut self, stmts: &[parse::Expr], span: Span) -> Vec<Expr> {
+    let mut block: Vec<Expr> = stmts.iter().map(|stmt| self.lower_expr(stmt)).collect();
+    if block.is_empty() {
+      block.push(Expr::zero(Type::Int64, span));
+    }
+    block
+  }
+
+  fn lower_expr(&mut self, expr: &parse::Expr) -> Expr {
+    let span = expr.span;
+    let ty = self.analysis.type_of(expr);
+    let kind = match &expr.kind {
+      Ast::Number(num) => ExprKind::Int(literal(num)),
+      Ast::UnaryPrefix(op, rhs) if op == "-" && matches!(rhs.kind, Ast::Number(_)) => match &rhs.kind {
+        Ast::Number(num) => ExprKind::Int(literal(num).wrapping_neg()),
+        _ => unreachable!(),
+      },
+      Ast::Bool(b) => ExprKind::Bool(*b),
+      Ast::Symbol(_) => match self.variable(self.resolve(expr)) {
+        Ok(local) => ExprKind::Local(local),
+        Err(global) => ExprKind::Global(global),
+      },
+      Ast::UnaryPrefix(op, rhs) => {
+        let rhs = self.lower_expr(rhs);
+        let (op, ty) = match op.as_str() {
+          "not" => (UnaryOp::Not, Type::Bool),
+          "-" => (UnaryOp::Neg, rhs.ty),
+          _ => (UnaryOp::BitNot, rhs.ty),
+        };
+        return Expr::new(ExprKind::Unary(op, Box::new(rhs)), ty, span);
+      }
+      // `a and b` is `if a then b else false`, `a or b` is `if a then true else b`
+      Ast::BinaryInfix(lhs, op, rhs) if op == "and" || op == "or" => {
+        let (lhs, rhs) = (self.lower_expr(lhs), self.lower_expr(rhs).cast(Type::Bool));
+        let decided = Expr::new(ExprKind::Bool(op == "or"), Type::Bool, span);
+        let (then, otherwise) = if op == "and" { (rhs, decided) } else { (decided, rhs) };
+        ExprKind::If(Box::new(lhs), vec![then], vec![otherwise])
+      }
+      Ast::BinaryInfix(lhs, op, rhs) if op == "/" || op == "%" => {
+        let lhs = self.lower_expr(lhs);
+        let rhs = self.lower_expr(rhs).cast(lhs.ty);
+        let op = if op == "/" { DivOp::Div } else { DivOp::Rem };
+        let ty = lhs.ty;
+        let by_zero = self.trap("Division by zero", span);
+        let overflow = (!ty.is_unsigned()).then(|| self.trap("Division overflow", span));
+        return Expr::new(ExprKind::Divide(Box::new(lhs), op, Box::new(rhs), by_zero, overflow), ty, span);
+      }
+      Ast::BinaryInfix(lhs, op, rhs) => {
+        let lhs = self.lower_expr(lhs);
+        let rhs = self.lower_expr(rhs).cast(lhs.ty);
+        let op = BinaryOp::from_symbol(op);
+        let ty = if op.is_comparison() { Type::Bool } else { lhs.ty };
+        return Expr::new(ExprKind::Binary(Box::new(lhs), op, Box::new(rhs)), ty, span);
+      }
+      // each `elsif` is an `if` in the previous branch's else, and a missing
+      // else is a zero
+      Ast::If(branches, otherwise) => {
+        let branches: Vec<(Expr, Vec<Expr>)> = branches.iter()
+          .map(|(cond, stmts)| (self.lower_expr(cond), self.nested(|l| l.lower_block(stmts, ty, span))))
+          .collect();
+        let mut otherwise = match otherwise {
+          Some(stmts) => self.nested(|l| l.lower_block(stmts, ty, span)),
+          None => vec![Expr::zero(ty, span)],
+        };
+        for (cond, then) in branches.into_iter().rev() {
+          otherwise = vec![Expr::new(ExprKind::If(Box::new(cond), then, otherwise), ty, span)];
+        }
+        return otherwise.pop().unwrap();
+      }
+      Ast::While(cond, stmts) => {
+        let cond = self.lower_expr(cond);
+        ExprKind::While(Box::new(cond), self.nested(|l| l.lower_stmts(stmts, span)))
+      }
+      Ast::Break => ExprKind::Break,
+      Ast::Continue => ExprKind::Continue,
+      Ast::Return(value) => {
+        let value = match value {
+          Some(value) => self.lower_expr(value),
+          None => Expr::zero(Type::Int64, span),
+        };
+        ExprKind::Return(Box::new(value.cast(self.ret)))
+      }
+      Ast::Let(_, _, mutable, value) => {
+        let value = self.lower_expr(value).cast(ty);
+        let def = self.resolve(expr);
+        if self.top_level {
+          ExprKind::SetGlobal(self.global(def), Box::new(value))
+        } else {
+          ExprKind::Let(self.define(def, *mutable), Box::new(value))
+        }
+      }
+      Ast::Assign(_, value) => {
+        let value = Box::new(self.lower_expr(value).cast(ty));
+        match self.variable(self.resolve(expr)) {
+          Ok(local) => ExprKind::Assign(local, value),
+          Err(global) => ExprKind::SetGlobal(global, value),
+        }
+      }
+      // definitions are hoisted out and lowered on their own
+      Ast::FuncDef(..) => ExprKind::Int(0),
+      Ast::FuncCall(_, args) => {
+        let func = self.resolve(expr);
+        let params = match &self.analysis.resolution.def(func).kind {
+          DefKind::Function(params) => params.clone(),
+          _ => unreachable!(),
+        };
+        let args = args.iter().zip(params)
+          .map(|(arg, param)| self.lower_expr(arg).cast(self.def_type(param)))
+          .collect();
+        ExprKind::Call(self.functions[&func], args)
+      }
+    };
+    Expr::new(kind, ty, span)
+  }
+}
+
+fn collect_functions<'a>(exprs: &'a [parse::Expr], functions: &mut Vec<&'a parse::Expr>) {
+  for expr in exprs {
+    match &expr.kind {
+      Ast::FuncDef(_, _, _, stmts) => {
+        functions.push(expr);
+        collect_functions(stmts, functions);
+      }
+      Ast::If(branches, otherwise) => {
+        for (_, stmts) in branches {
+          collect_functions(stmts, functions);
+        }
+        if let Some(stmts) = otherwise {
+          collect_functions(stmts, functions);
+        }
+      }
+      Ast::While(_, stmts) => collect_functions(stmts, functions),
+      _ => {}
+    }
+  }
+}
+
+// Lowers a program `analyse` accepted.
+pub fn lower(src: &str, program: &[parse::Expr], analysis: &Analysis) -> Program {
+  lower_program(src, program, analysis, None)
+}
+
+// Lowers a program `analyse_after` accepted, as the REPL does with each
+// input. Main only runs the statements after the first `settled`, and
+// bindings at its top level are globals, so that later inputs still see
+// them. Earlier inputs' functions come first, so they keep their ids.
+pub fn lower_after(src: &str, program: &[parse::Expr], analysis: &Analysis, settled: usize) -> Program {
+  lower_program(src, program, analysis, Some(settled))
+}
+
+fn lower_program(src: &str, program: &[parse::Expr], analysis: &Analysis, settled: Option<usize>) -> Program {
+  let mut defs = vec![];
+  collect_functions(program, &mut defs);
+
+  let mut lowerer = Lowerer {
+    src,
+    analysis,
+    functions: HashMap::new(),
+    locals: vec![],
+    local_ids: HashMap::new(),
+    ret: Type::Int64,
+    globals: vec![],
+    global_ids: HashMap::new(),
+    top_level: false,
+  };
+  for (i, def) in defs.iter().enumerate() {
+    lowerer.functions.insert(lowerer.resolve(def), FuncId(i));
+  }
+
+  let mut functions = vec![];
+  for (i, def) in defs.iter().enumerate() {
+    if let Ast::FuncDef(name, _, _, stmts) = &def.kind {
+      let id = lowerer.resolve(def);
+      let params = match &analysis.resolution.def(id).kind {
+        DefKind::Function(params) => params.clone(),
+        _ => unreachable!(),
+      };
+      let ret = lowerer.def_type(id);
+      functions.push(lowerer.function(FuncId(i), name.clone(), &params, ret, stmts, def.span));
+    }
+  }
+  lowerer.top_level = settled.is_some();
+  let stmts = &program[settled.unwrap_or(0)..];
+  let main = vec!["main".to_string()];
+  functions.push(lowerer.function(FuncId(defs.len()), main, &[], Type::Int64, stmts, Span { start: 0, end: src.len() }));
+  Program { functions, globals: lowerer.globals }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{mangle, ExprKind, LocalId, Type};
+  use crate::Session;
+
+  #[test]
+  fn namespaced_functions_are_mangled() {
+    let name = |s: &str| s.split("::").map(str::to_string).collect::<Vec<_>>();
+    assert_eq!(mangle(&name("math::is_even?")), "sfd_math_Nis__even_Q");
+    assert_ne!(mangle(&name("a::b")), mangle(&name("a_Nb")));
+  }
+
+  #[test]
+  fn sugar_is_lowered_to_nested_ifs() {
+    let src = "let x Int32 = 1\nlet x = x + 1\nif x == 1 and true\n  1\nelsif x == 2\n  2\nend\n";
+    let program = Session::new(src).check().unwrap();
+    let main = program.main();
+    assert_eq!(main.locals.len(), 2);
+    assert_eq!(main.locals[1].ty, Type::Int32);
+
+    match &main.body[2].kind {
+      ExprKind::If(cond, _, otherwise) => {
+        // `and` is an if of its own, `elsif` an if in the else, ending in zero
+        assert!(matches!(cond.kind, ExprKind::If(..)));
+        match &otherwise[0].kind {
+          ExprKind::If(cond, _, otherwise) => {
+            match &cond.kind {
+              ExprKind::Binary(lhs, _, _) => assert!(matches!(lhs.kind, ExprKind::Local(LocalId(1)))),
+              _ => unreachable!(),
+            }
+            assert!(matches!(otherwise[0].kind, ExprKind::Int(0)));
+          }
+          _ => unreachable!(),
+        }
+      }
+      _ => unreachable!(),
+    }
+  }
+}
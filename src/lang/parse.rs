@@ -6,11 +6,22 @@ use super::error::*;
 #[derive(Debug, Clone)]
 pub enum ExprKind {
   Number(String),
+  Bool(bool),
   Symbol(Vec<String>),
    
+  UnaryPrefix(String, Box<Expr>),
   BinaryInfix(Box<Expr>, String, Box<Expr>),
 
-  FuncDef(Vec<String>, Vec<(String, String)>, Option<String>, Vec<Expr>), // namespaced name, typed parameters, (return type), stmts
+  If(Vec<(Expr, Vec<Expr>)>, Option<Vec<Expr>>), // (condition, stmts) for the 'if' and each 'elsif', ('else' stmts)
+  While(Box<Expr>, Vec<Expr>), // condition, stmts
+  Break,
+  Continue,
+  Return(Option<Box<Expr>>), // (value)
+
+  Let(String, Option<String>, bool, Box<Expr>), // name, (type), mutable, value
+  Assign(Vec<String>, Box<Expr>), // name, value
+
+  FuncDef(Vec<String>, Vec<(String, String, Span)>, Option<String>, Vec<Expr>), // namespaced name, typed parameters, (return type), stmts
   FuncCall(Vec<String>, Vec<Expr>), // namespaced name, args
 }
 
@@ -25,10 +36,22 @@ pub struct Parser<'a> {
   tokens: Peekable<Iter<'a, Token>>
 }
 
-fn infix_bp(op: char) -> Option<(u8, u8)> {
+fn infix_bp(op: &str) -> Option<(u8, u8)> {
+  match op {
+    "=" => Some((2, 1)),
+    "or" => Some((4, 5)),
+    "and" => Some((6, 7)),
+    "==" | "!=" | "<" | "<=" | ">" | ">=" => Some((8, 9)),
+    "+" | "-" => Some((10, 11)),
+    "*" | "/" | "%" => Some((12, 13)),
+    _ => None,
+  }
+}
+
+fn prefix_bp(op: &str) -> Option<u8> {
   match op {
-    '+' | '-' => Some((10, 11)),
-    '*' | '/' => Some((12, 13)),
+    "not" => Some(8),
+    "-" | "~" => Some(14),
     _ => None,
   }
 }
@@ -46,15 +69,6 @@ impl<'a> Parser<'a> {
     } 
   }
   
-  fn next_if(&mut self, kind: TokenKind) -> IResult<Token> {
-    let t = self.peek_no_eof()?;
-    if t.kind == kind {
-      return self.next_no_eof();
-    }
-    
-    Ok(t)
-  }
-  
   fn expect_no_next(&mut self, kind: TokenKind) -> IResult<Token> {
     let t = self.peek_no_eof()?;
     if t.kind == kind {
@@ -74,7 +88,7 @@ impl<'a> Parser<'a> {
     let span = self.peek_no_eof()?.span;
     for kind in kinds.clone() {
       return match self.expect_no_next(kind) {
-        Err(e) => continue,
+        Err(_) => continue,
         Ok(o) => Ok(o)
       }
     }
@@ -114,12 +128,38 @@ impl<'a> Parser<'a> {
           span: tok.span 
         })
       },
+      TokenKind::LParen => {
+        self.next_no_eof()?;
+        let inner = self.parse_expr()?;
+        let end = self.expect_next(TokenKind::RParen)?.span.end;
+        Ok(Expr {
+          kind: inner.kind,
+          span: span(tok.span.start, end)
+        })
+      },
+      TokenKind::KeywordTrue | TokenKind::KeywordFalse => {
+        self.next_no_eof()?;
+        Ok(Expr {
+          kind: ExprKind::Bool(tok.kind == TokenKind::KeywordTrue),
+          span: tok.span
+        })
+      },
+      TokenKind::Operator => {
+        let op = self.span_str(tok.span).to_string();
+        let r_bp = prefix_bp(&op)
+          .ok_or_else(|| lang_error_fatal("Expected an expression", tok.span))?;
+        self.next_no_eof()?;
+        let rhs = self.parse_binary(r_bp)?;
+        let end = rhs.span.end;
+        Ok(Expr {
+          kind: ExprKind::UnaryPrefix(op, Box::new(rhs)),
+          span: span(tok.span.start, end)
+        })
+      },
       TokenKind::Symbol => {
         let namespaced = self.parse_namespace_name()?;
-        if self.tokens.peek().is_some() {
-          if self.peek_no_eof()?.kind == TokenKind::LParen {
-            return self.parse_funccall(namespaced, tok.span.start);
-          }
+        if self.tokens.peek().is_some() && self.peek_no_eof()?.kind == TokenKind::LParen {
+          return self.parse_funccall(namespaced, tok.span.start);
         }
 
         Ok(Expr { 
@@ -146,7 +186,7 @@ impl<'a> Parser<'a> {
         _ => break
       }?.to_string();
 
-      if let Some((l_bp, r_bp)) = infix_bp(op.chars().next().unwrap()) {
+      if let Some((l_bp, r_bp)) = infix_bp(&op) {
         if l_bp < min_bp {
           break;
         }
@@ -155,8 +195,17 @@ impl<'a> Parser<'a> {
         let rhs = self.parse_binary(r_bp)?;
         let end = rhs.span.end;
         
+        let kind = if op == "=" {
+          match lhs.kind {
+            ExprKind::Symbol(name) => ExprKind::Assign(name, Box::new(rhs)),
+            _ => return Err(lang_error("Can only assign to a variable", lhs.span)),
+          }
+        } else {
+          ExprKind::BinaryInfix(Box::new(lhs), op, Box::new(rhs))
+        };
+        
         lhs = Expr {
-          kind: ExprKind::BinaryInfix(Box::new(lhs), op, Box::new(rhs)),
+          kind,
           span: span(start, end)
         };
         continue;
@@ -194,7 +243,7 @@ impl<'a> Parser<'a> {
     Ok(res)
   } 
   
-  fn parse_parameters(&mut self) -> IResult<Vec<(String, String)>> {
+  fn parse_parameters(&mut self) -> IResult<Vec<(String, String, Span)>> {
     let mut res = vec![];
     loop {
       if self.peek_no_eof()?.kind != TokenKind::Symbol {
@@ -202,7 +251,8 @@ impl<'a> Parser<'a> {
       }
       let name = self.expect_next(TokenKind::Symbol)?;
       let ntype = self.expect_next(TokenKind::Type)?;
-      res.push((self.span_str(name.span).to_string(), self.span_str(ntype.span).to_string()));
+      let (pname, ptype) = (self.span_str(name.span).to_string(), self.span_str(ntype.span).to_string());
+      res.push((pname, ptype, span(name.span.start, ntype.span.end)));
       if let TokenKind::Comma = self.peek_no_eof()?.kind {
         self.next_no_eof()?;
       } else {
@@ -226,10 +276,21 @@ impl<'a> Parser<'a> {
     Ok(res)
   }
 
-  fn parse_stmts_till(&mut self, kind: TokenKind) -> IResult<Vec<Expr>> {
+  fn skip_newlines(&mut self) -> IResult<()> {
+    while self.peek_no_eof()?.kind == TokenKind::Newline {
+      self.next_no_eof()?;
+    }
+    Ok(())
+  }
+
+  fn parse_stmts_till(&mut self, kinds: &[TokenKind]) -> IResult<Vec<Expr>> {
     let mut res = vec![];
     let mut errs = vec![];
-    while self.peek_no_eof()?.kind != kind {
+    loop {
+      self.skip_newlines()?;
+      if kinds.contains(&self.peek_no_eof()?.kind) {
+        break;
+      }
       let stmt = self.parse_stmt();
       match stmt {
         Ok(e) => res.push(e),
@@ -238,13 +299,21 @@ impl<'a> Parser<'a> {
             return Err(e);
           }
           errs.push(e);
+          // recover at the end of the broken statement
+          while !matches!(self.peek_no_eof()?.kind, TokenKind::Semicolon | TokenKind::Newline) {
+            self.next_no_eof()?;
+          }
         }
       } 
 
-      if errs.is_empty() {
-        self.expect_or(vec![TokenKind::Semicolon, TokenKind::Newline])?;
-        self.next_no_eof()?;
-      }
+      self.expect_or(vec![TokenKind::Semicolon, TokenKind::Newline])?;
+      self.next_no_eof()?;
+    }
+    
+    if !errs.is_empty() {
+      let start = errs.first().unwrap().span.start;
+      let end = errs.last().unwrap().span.end;
+      return Err(lang_errors(span(start, end), errs));
     }
     
     Ok(res)
@@ -261,10 +330,10 @@ impl<'a> Parser<'a> {
     }
     let args = self.parse_arguments()?;
     let end = self.expect_next(TokenKind::RParen)?.span.end;
-    return Ok(Expr {
+    Ok(Expr {
       kind: ExprKind::FuncCall(name, args),
       span: span(start, end)
-    }) 
+    })
   }
 
   fn parse_funcdef(&mut self) -> IResult<Expr> {
@@ -278,7 +347,7 @@ impl<'a> Parser<'a> {
     if t.kind == TokenKind::LParen {
       self.next_no_eof()?;
       params = self.parse_parameters()?;
-      self.expect_next(TokenKind::RParen);
+      self.expect_next(TokenKind::RParen)?;
       t = self.peek_no_eof()?;
     } 
     
@@ -290,7 +359,7 @@ impl<'a> Parser<'a> {
     
     self.expect_next(TokenKind::Newline)?;
     
-    let exprs = self.parse_stmts_till(TokenKind::KeywordEnd)?;
+    let exprs = self.parse_stmts_till(&[TokenKind::KeywordEnd])?;
     let end = self.next_no_eof()?.span.end; // skip 'end'
 
     Ok(Expr {
@@ -299,17 +368,110 @@ impl<'a> Parser<'a> {
     })
   }
   
+  fn parse_if(&mut self) -> IResult<Expr> {
+    let start = self.next_no_eof()?.span.start; // skip 'if'
+    let mut branches = vec![];
+    let mut otherwise = None;
+    let end;
+    
+    loop {
+      let cond = self.parse_expr()?;
+      self.expect_or(vec![TokenKind::Newline, TokenKind::Semicolon])?;
+      self.next_no_eof()?;
+      let stmts = self.parse_stmts_till(&[TokenKind::KeywordElsif, TokenKind::KeywordElse, TokenKind::KeywordEnd])?;
+      branches.push((cond, stmts));
+      
+      let t = self.next_no_eof()?;
+      match t.kind {
+        TokenKind::KeywordElsif => continue,
+        TokenKind::KeywordElse => {
+          otherwise = Some(self.parse_stmts_till(&[TokenKind::KeywordEnd])?);
+          end = self.next_no_eof()?.span.end; // skip 'end'
+        }
+        _ => end = t.span.end,
+      }
+      break;
+    }
+    
+    Ok(Expr {
+      kind: ExprKind::If(branches, otherwise),
+      span: span(start, end),
+    })
+  }
+  
+  fn parse_while(&mut self) -> IResult<Expr> {
+    let start = self.next_no_eof()?.span.start; // skip 'while'
+    let cond = self.parse_expr()?;
+    self.expect_or(vec![TokenKind::Newline, TokenKind::Semicolon])?;
+    self.next_no_eof()?;
+    
+    let stmts = self.parse_stmts_till(&[TokenKind::KeywordEnd])?;
+    let end = self.next_no_eof()?.span.end; // skip 'end'
+    
+    Ok(Expr {
+      kind: ExprKind::While(Box::new(cond), stmts),
+      span: span(start, end),
+    })
+  }
+  
+  fn parse_let(&mut self) -> IResult<Expr> {
+    let t = self.next_no_eof()?; // skip 'let' or 'var'
+    let mutable = t.kind == TokenKind::KeywordVar;
+    let name = self.expect_next(TokenKind::Symbol)?;
+    let mut ntype = None;
+    
+    if self.peek_no_eof()?.kind == TokenKind::Type {
+      let t = self.next_no_eof()?;
+      ntype = Some(self.span_str(t.span).to_string());
+    }
+    
+    let eq = self.expect_next(TokenKind::Operator)?;
+    if self.span_str(eq.span) != "=" {
+      return Err(lang_error(&format!("Expected \"=\" got {:?}!", self.span_str(eq.span)), eq.span));
+    }
+    
+    let value = self.parse_stmt()?;
+    let end = value.span.end;
+    
+    Ok(Expr {
+      kind: ExprKind::Let(self.span_str(name.span).to_string(), ntype, mutable, Box::new(value)),
+      span: span(t.span.start, end),
+    })
+  }
+  
   fn parse_stmt(&mut self) -> IResult<Expr> {
+    self.skip_newlines()?;
     let p = self.peek_no_eof()?;
     match p.kind {
       TokenKind::KeywordDef => self.parse_funcdef(),
+      TokenKind::KeywordIf => self.parse_if(),
+      TokenKind::KeywordWhile => self.parse_while(),
+      TokenKind::KeywordLet | TokenKind::KeywordVar => self.parse_let(),
+      TokenKind::KeywordReturn => {
+        self.next_no_eof()?;
+        let value_follows = self.peek_no_borrow().map(|t| !matches!(t.kind, 
+          TokenKind::Newline | TokenKind::Semicolon | TokenKind::KeywordEnd | TokenKind::Eof
+        )).unwrap_or(false);
+        if !value_follows {
+          return Ok(Expr { kind: ExprKind::Return(None), span: p.span });
+        }
+        
+        let value = self.parse_expr()?;
+        let end = value.span.end;
+        Ok(Expr { kind: ExprKind::Return(Some(Box::new(value))), span: span(p.span.start, end) })
+      },
+      TokenKind::KeywordBreak | TokenKind::KeywordContinue => {
+        self.next_no_eof()?;
+        let kind = if p.kind == TokenKind::KeywordBreak { ExprKind::Break } else { ExprKind::Continue };
+        Ok(Expr { kind, span: p.span })
+      },
       _ => self.parse_expr()
     }
   }
 
   fn parse_exprs(&mut self) -> IResult<Vec<Expr>> {
     let mut exprs = vec![];
-    let mut errors = vec![];
+    let errors = vec![];
     
 
     loop {
@@ -361,4 +523,101 @@ pub fn parse(src: &str) -> IResult<Vec<Expr>> {
       Err(errs.pop().unwrap())
     }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::{parse, Expr, ExprKind};
+
+  // Spells out the structure of an expression, e.g `(+ 1 (* 2 3))`.
+  fn sexp(expr: &Expr) -> String {
+    match &expr.kind {
+      ExprKind::Number(n) => n.clone(),
+      ExprKind::Bool(b) => b.to_string(),
+      ExprKind::Symbol(name) => name.join("::"),
+      ExprKind::UnaryPrefix(op, rhs) => format!("({} {})", op, sexp(rhs)),
+      ExprKind::BinaryInfix(lhs, op, rhs) => format!("({} {} {})", op, sexp(lhs), sexp(rhs)),
+      ExprKind::If(branches, otherwise) => {
+        let mut s = "(if".to_string();
+        for (cond, stmts) in branches {
+          s += &format!(" {} {}", sexp(cond), block(stmts));
+        }
+        if let Some(stmts) = otherwise {
+          s += &format!(" else {}", block(stmts));
+        }
+        s + ")"
+      }
+      ExprKind::While(cond, stmts) => format!("(while {} {})", sexp(cond), block(stmts)),
+      ExprKind::Break => "break".to_string(),
+      ExprKind::Continue => "continue".to_string(),
+      ExprKind::Return(None) => "return".to_string(),
+      ExprKind::Return(Some(value)) => format!("(return {})", sexp(value)),
+      ExprKind::Let(name, ntype, mutable, value) => {
+        let ntype = ntype.as_ref().map(|t| format!(" {}", t)).unwrap_or_default();
+        format!("({} {}{} {})", if *mutable { "var" } else { "let" }, name, ntype, sexp(value))
+      }
+      ExprKind::Assign(name, value) => format!("(= {} {})", name.join("::"), sexp(value)),
+      ExprKind::FuncDef(name, params, ret, stmts) => {
+        let params: Vec<String> = params.iter().map(|(n, t, _)| format!("{} {}", n, t)).collect();
+        let ret = ret.as_ref().map(|t| format!(" -> {}", t)).unwrap_or_default();
+        format!("(def {} ({}){} {})", name.join("::"), params.join(", "), ret, block(stmts))
+      }
+      ExprKind::FuncCall(name, args) => {
+        let args: Vec<String> = args.iter().map(sexp).collect();
+        format!("({} {})", name.join("::"), args.join(" "))
+      }
+    }
+  }
+
+  fn block(stmts: &[Expr]) -> String {
+    format!("[{}]", stmts.iter().map(sexp).collect::<Vec<_>>().join(" "))
+  }
+
+  fn parsed(src: &str) -> Vec<String> {
+    parse(src).unwrap().iter().map(sexp).collect()
+  }
+
+  #[test]
+  fn operators_bind_by_precedence() {
+    assert_eq!(parsed("1 + 2 * 3 - 4 % 5\n"), ["(- (+ 1 (* 2 3)) (% 4 5))"]);
+    assert_eq!(parsed("a < b + 1 and not c or d == e\n"), ["(or (and (< a (+ b 1)) (not c)) (== d e))"]);
+    assert_eq!(parsed("a or b and c\n"), ["(or a (and b c))"]);
+    // comparisons are munched greedily and associate to the left
+    assert_eq!(parsed("a<=b != c>=d\n"), ["(>= (!= (<= a b) c) d)"]);
+    assert_eq!(parsed("a<=-b\n"), ["(<= a (- b))"]);
+  }
+
+  #[test]
+  fn elsif_chains_keep_every_branch() {
+    assert_eq!(parsed("if a\n  1\nelsif b\n  2\nelsif c; 3\nelse\n  4\nend\n"), ["(if a [1] b [2] c [3] else [4])"]);
+    assert_eq!(parsed("if a\nend\n"), ["(if a [])"]);
+  }
+
+  #[test]
+  fn loops_hold_break_and_continue() {
+    let src = "while i < 10\n  if i == 5\n    break\n  end\n  continue\nend\n";
+    assert_eq!(parsed(src), ["(while (< i 10) [(if (== i 5) [break]) continue])"]);
+  }
+
+  #[test]
+  fn bindings_and_assignments() {
+    assert_eq!(parsed("let x Int32 = 1\nvar y = x\ny = y + 1\n"), ["(let x Int32 1)", "(var y x)", "(= y (+ y 1))"]);
+    assert_eq!(parsed("a = b = 2\n"), ["(= a (= b 2))"]);
+    assert!(parse("1 = 2\n").is_err());
+  }
+
+  #[test]
+  fn groups_and_prefix_operators() {
+    assert_eq!(parsed("(a + b) * c\n"), ["(* (+ a b) c)"]);
+    assert_eq!(parsed("-x * 2\n"), ["(* (- x) 2)"]);
+    assert_eq!(parsed("~-(x - 1)\n"), ["(~ (- (- x 1)))"]);
+    assert_eq!(parsed("not a == b\n"), ["(not (== a b))"]);
+    assert!(parse("(a + b\n").is_err());
+  }
+
+  #[test]
+  fn returns_with_and_without_a_value() {
+    let src = "def f(a Int64) -> Int64\n  if a > 0\n    return a\n  end\n  return\nend\n";
+    assert_eq!(parsed(src), ["(def f (a Int64) -> Int64 [(if (> a 0) [(return a)]) return])"]);
+    assert_eq!(parsed("return; 1\n"), ["return", "1"]);
+  }
+}
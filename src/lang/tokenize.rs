@@ -1,6 +1,5 @@
 use std::{iter::{Peekable, Enumerate}, str::Chars};
 use super::error::*;
-use itertools::Itertools;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TokenKind {
@@ -14,6 +13,17 @@ pub enum TokenKind {
   
   KeywordDef,
   KeywordEnd,
+  KeywordIf,
+  KeywordElsif,
+  KeywordElse,
+  KeywordWhile,
+  KeywordBreak,
+  KeywordContinue,
+  KeywordReturn,
+  KeywordLet,
+  KeywordVar,
+  KeywordTrue,
+  KeywordFalse,
   
   Semicolon, // ';'
   Comma,     // ','
@@ -38,7 +48,7 @@ struct Tokenizer<'a> {
 
 impl<'a> Tokenizer<'a> {
   fn peek_eof(&mut self) -> (usize, char) {
-    self.chars.peek().unwrap_or(&(self.src.len(), '\0')).clone()
+    *self.chars.peek().unwrap_or(&(self.src.len(), '\0'))
   }
    
   fn next_eof(&mut self) -> (usize, char) {
@@ -72,12 +82,12 @@ impl<'a> Tokenizer<'a> {
         self.next_eof();
       }
       
-      return Ok(Token { kind: TokenKind::Newline, span: span_single(self.src.len()) });
+      return Ok(Token { kind: TokenKind::Newline, span: span(start, self.position()) });
     }
     
     // IF CHARACTER IS DIGIT
-    if c.is_digit(10) {
-      while self.peek_eof().1.is_digit(10) { self.next_eof(); }
+    if c.is_ascii_digit() {
+      while self.peek_eof().1.is_ascii_digit() { self.next_eof(); }
       let after = self.peek_eof().1;
  
       // TODO: more than only base 10
@@ -91,25 +101,24 @@ impl<'a> Tokenizer<'a> {
       // If the number is a floating point...
       if self.peek_eof().1 == '.' {
         self.chars.next();
-        if !self.peek_eof().1.is_digit(10) {
+        if !self.peek_eof().1.is_ascii_digit() {
           return Err(lang_error("Expected a digit", span_single(self.position())))
         }
-        while self.peek_eof().1.is_digit(10) { self.next_eof(); }
+        while self.peek_eof().1.is_ascii_digit() { self.next_eof(); }
 
         let end = self.position();
         return Ok(Token { kind: TokenKind::Number, span: span(start, end) })
       }
 
       let end = self.position();
-      return Ok(Token { kind: TokenKind::Number, span: span(start, end)});
+      Ok(Token { kind: TokenKind::Number, span: span(start, end)})
     } else if c.is_alphabetic() {
       self.next_eof();
-      while self.peek_eof().1.is_alphanumeric() { self.next_eof(); }
+      while self.peek_eof().1.is_alphanumeric() || self.peek_eof().1 == '_' { self.next_eof(); }
       
       // e.g isdigit?
-      match self.peek_eof().1 {
-        '?' => { self.next_eof(); },
-        _ => {}
+      if self.peek_eof().1 == '?' {
+        self.next_eof();
       }
       
 
@@ -122,53 +131,46 @@ impl<'a> Tokenizer<'a> {
       let kind = match s {
         "def" => TokenKind::KeywordDef,
         "end" => TokenKind::KeywordEnd,
+        "if" => TokenKind::KeywordIf,
+        "elsif" => TokenKind::KeywordElsif,
+        "else" => TokenKind::KeywordElse,
+        "while" => TokenKind::KeywordWhile,
+        "break" => TokenKind::KeywordBreak,
+        "continue" => TokenKind::KeywordContinue,
+        "return" => TokenKind::KeywordReturn,
+        "let" => TokenKind::KeywordLet,
+        "var" => TokenKind::KeywordVar,
+        "true" => TokenKind::KeywordTrue,
+        "false" => TokenKind::KeywordFalse,
+        "and" | "or" | "not" => TokenKind::Operator,
         _ => TokenKind::Symbol,
       };
       
-      return Ok(Token { kind, span: span(start, end) });
+      Ok(Token { kind, span: span(start, end) })
     } else {
-      let kind = match self.peek_eof().1 {
+      let kind = match c {
         ';' => TokenKind::Semicolon,
         ',' => TokenKind::Comma,
         '(' => TokenKind::LParen,
         ')' => TokenKind::RParen,
-        ':' => {
-          self.next_eof();
-          match self.peek_eof().1 {
-            ':' => { TokenKind::Namespace }
-            _ => TokenKind::Operator,
-          }
-        }
-        '-' => {
-          self.next_eof();
-          match self.peek_eof().1 {
-            '>' => { TokenKind::Arrow }
-            _ => TokenKind::Operator,
-          }
-        }
         _ => TokenKind::Operator
       };
+      self.next_eof();
 
       if kind != TokenKind::Operator {
-        self.next_eof();
         return Ok(Token { kind, span: span(start, self.position())})
       }
       
-      // constructable operators
-      loop {
-        match self.peek_eof().1 {
-          '+' | '-' | '*' | '/' => { self.next_eof(); }
-          _ => break
-        }
-      }
-      
-      let end = self.position();
-      if end == start { 
-        self.next_eof();
-        return Err(lang_error("Bad character(s)", span(start, self.position())))
-      }
+      // operators are munched greedily, e.g '<=' rather than '<' then '='
+      let kind = match (c, self.peek_eof().1) {
+        (':', ':') => { self.next_eof(); TokenKind::Namespace },
+        ('-', '>') => { self.next_eof(); TokenKind::Arrow },
+        ('=' | '!' | '<' | '>', '=') => { self.next_eof(); TokenKind::Operator },
+        ('+' | '-' | '*' | '/' | '%' | '<' | '>' | '=' | '~' | ':', _) => TokenKind::Operator,
+        _ => return Err(lang_error("Bad character(s)", span(start, self.position())))
+      };
       
-      Ok(Token { kind, span: span(start, end) })
+      Ok(Token { kind, span: span(start, self.position()) })
     }
     
   }
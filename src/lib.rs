@@ -0,0 +1,14 @@
+pub mod lang;
+pub mod backend;
+pub mod codegen;
+pub mod codegen_c;
+pub mod codegen_wasm;
+#[cfg(feature = "llvm")]
+#[path = "codegen-llvm.rs"]
+pub mod codegen_llvm;
+pub mod interp;
+pub mod link;
+mod session;
+
+pub use link::LinkOptions;
+pub use session::{Artifacts, Diagnostics, Session};
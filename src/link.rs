@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::lang::error::{lang_error_global, LangError};
+
+// How an object file gets turned into an executable by the system toolchain.
+#[derive(Debug, Clone)]
+pub struct LinkOptions {
+  pub linker: String,
+  pub libs: Vec<String>,
+  pub args: Vec<String>,
+}
+
+impl Default for LinkOptions {
+  fn default() -> Self {
+    LinkOptions { linker: "cc".to_string(), libs: vec![], args: vec![] }
+  }
+}
+
+// Links the objects at `objects` into an executable at `output`.
+pub fn link(objects: &[PathBuf], output: &Path, opts: &LinkOptions) -> Result<(), LangError> {
+  let mut cmd = Command::new(&opts.linker);
+  cmd.args(objects).arg("-o").arg(output);
+  cmd.args(opts.libs.iter().map(|l| format!("-l{}", l)));
+  cmd.args(&opts.args);
+
+  let out = cmd.output()
+    .map_err(|e| lang_error_global(&format!("Couldn't run linker '{}': {}", opts.linker, e)))?;
+  if !out.status.success() {
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    let mut msg = format!("Linking with '{}' failed ({})", opts.linker, out.status);
+    if !stderr.trim().is_empty() {
+      msg.push('\n');
+      msg.push_str(stderr.trim_end());
+    }
+    return Err(lang_error_global(&msg));
+  }
+
+  Ok(())
+}
+
+// Links an in-memory object, going through a temporary file next to `output`.
+pub fn link_object(object: &[u8], output: &Path, opts: &LinkOptions) -> Result<(), LangError> {
+  link_temporary(object, "o", output, opts)
+}
+
+// Compiles and links C source with the linker, which is expected to be a C
+// compiler driver such as cc.
+pub fn link_source(source: &str, output: &Path, opts: &LinkOptions) -> Result<(), LangError> {
+  link_temporary(source.as_bytes(), "c", output, opts)
+}
+
+fn link_temporary(contents: &[u8], extension: &str, output: &Path, opts: &LinkOptions) -> Result<(), LangError> {
+  let mut tmp = output.as_os_str().to_owned();
+  tmp.push(format!(".{}.{}", std::process::id(), extension));
+  let tmp = PathBuf::from(tmp);
+
+  std::fs::write(&tmp, contents)
+    .map_err(|e| lang_error_global(&format!("Couldn't write {}: {}", tmp.display(), e)))?;
+  let linked = link(std::slice::from_ref(&tmp), output, opts);
+  let _ = std::fs::remove_file(&tmp);
+  linked
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lang::error::LangErrorKind;
+
+  fn output(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("scaffold-link-{}-{}", name, std::process::id()))
+  }
+
+  fn leftovers(output: &Path) -> Vec<PathBuf> {
+    let prefix = output.file_name().unwrap().to_string_lossy().into_owned();
+    std::fs::read_dir(output.parent().unwrap()).unwrap()
+      .map(|e| e.unwrap().path())
+      .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(&prefix))
+      .collect()
+  }
+
+  #[test]
+  fn missing_linker() {
+    let out = output("linker");
+    let opts = LinkOptions { linker: "scaffold-no-such-linker".to_string(), ..LinkOptions::default() };
+    let err = link_object(b"", &out, &opts).unwrap_err();
+    assert!(matches!(err.kind, LangErrorKind::Global), "{:?}", err);
+    assert!(err.msg.starts_with("Couldn't run linker 'scaffold-no-such-linker': "), "{}", err.msg);
+    assert_eq!(leftovers(&out), Vec::<PathBuf>::new());
+  }
+
+  #[test]
+  fn missing_library() {
+    let out = output("library");
+    let opts = LinkOptions { libs: vec!["scaffold_no_such_lib".to_string()], ..LinkOptions::default() };
+    let err = link_source("int main(void) { return 0; }\n", &out, &opts).unwrap_err();
+    assert!(matches!(err.kind, LangErrorKind::Global), "{:?}", err);
+    let (status, stderr) = err.msg.split_once('\n').unwrap();
+    assert!(status.starts_with("Linking with 'cc' failed ("), "{}", err.msg);
+    assert!(stderr.contains("scaffold_no_such_lib"), "{}", err.msg);
+    assert_eq!(leftovers(&out), Vec::<PathBuf>::new());
+  }
+}
@@ -1,32 +1,278 @@
-use core::mem;
-use codegen::CodeGen;
-use lang::error::{report_error, LangErrorKind, LangError};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 
-mod lang;
-mod codegen;
+use scaffold::{backend, link, Diagnostics, LinkOptions, Session};
 
+mod repl;
 
-fn main() {
-  let src = std::fs::read_to_string("example.sfd").unwrap();
-  
-  let ran = CodeGen::new().compile(&src);
-
-  match ran {
-    Ok(e) => {
-      
-    },
-    Err(err) => {
-      match err.clone().kind {
-        LangErrorKind::Contextual(errs) => {
-          println!("{}", report_error(&src, err));
-          for e in errs {
-            println!("{}", report_error(&src, e));
-          }
-        }
-        _ => {
-          println!("{}", report_error(&src, err))
-        }
-      }
+const USAGE: &str = "\
+usage: scaffold <command> [options] <file.sfd>
+       scaffold repl
+
+commands:
+  build    compile a source file
+  check    parse and analyse a source file without generating code
+  run      compile a source file in memory and run it, exiting with
+           the value main returns
+  repl     evaluate definitions and expressions interactively
+
+options:
+  -o <path>           where to write the executable, or the object file or
+                      C source when there is no executable
+  --emit=<kinds>      comma separated list of tokens, ast, clif, llvm-ir,
+                      wat, obj, c, exe (tokens, ast and the IRs are printed,
+                      exe is the default)
+  --target=<triple>   target to generate code for, defaults to the host.
+                      x86_64, aarch64 and riscv64 triples get ELF, Mach-O or
+                      COFF objects as the triple says, wasm32 builds a .wasm
+                      module instead of an executable
+  --backend=<name>    cranelift, the default, llvm when built with the llvm
+                      feature, c to translate the program to C, or interp to
+                      evaluate the program without compiling it (run only)
+  -O                  optimize the generated code
+  -l <lib>            link against a library, e.g -lm
+  --link-arg=<arg>    pass an extra argument to the linker
+  --linker=<cmd>      program used to link executables, defaults to cc";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Emit {
+  Tokens,
+  Ast,
+  Clif,
+  LlvmIr,
+  Wat,
+  Obj,
+  C,
+  Exe,
+}
+
+struct Options {
+  command: String,
+  input: PathBuf,
+  output: Option<PathBuf>,
+  emit: Vec<Emit>,
+  target: Option<String>,
+  backend: String,
+  optimize: bool,
+  link: LinkOptions,
+}
+
+fn parse_emit(kinds: &str) -> Result<Vec<Emit>, String> {
+  kinds.split(',').map(|k| match k {
+    "tokens" => Ok(Emit::Tokens),
+    "ast" => Ok(Emit::Ast),
+    "clif" => Ok(Emit::Clif),
+    "llvm-ir" => Ok(Emit::LlvmIr),
+    "wat" => Ok(Emit::Wat),
+    "obj" => Ok(Emit::Obj),
+    "c" => Ok(Emit::C),
+    "exe" => Ok(Emit::Exe),
+    _ => Err(format!("Unknown emit kind '{}'", k)),
+  }).collect()
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+  let mut args = args.into_iter();
+  let command = args.next().ok_or("Missing command")?;
+  if command != "build" && command != "check" && command != "run" {
+    return Err(format!("Unknown command '{}'", command));
+  }
+
+  let mut input = None;
+  let mut output = None;
+  let mut emit = vec![];
+  let mut target = None;
+  let mut backend = "cranelift".to_string();
+  let mut optimize = false;
+  let mut link = LinkOptions::default();
+  while let Some(arg) = args.next() {
+    // options take their value either as the next argument or after a '='
+    let (flag, inline) = match arg.split_once('=') {
+      Some((f, v)) if f.starts_with('-') => (f.to_string(), Some(v.to_string())),
+      _ => (arg.clone(), None),
+    };
+    let mut value = |name: &str| inline.clone().or_else(|| args.next())
+      .ok_or_else(|| format!("Missing value for {}", name));
+
+    match flag.as_str() {
+      "-o" => output = Some(PathBuf::from(value("-o")?)),
+      "--emit" => emit.extend(parse_emit(&value("--emit")?)?),
+      "--target" => target = Some(value("--target")?),
+      "--backend" => backend = value("--backend")?,
+      "-O" => optimize = true,
+      "-l" => link.libs.push(value("-l")?),
+      l if l.starts_with("-l") && !l.starts_with("--") => link.libs.push(l[2..].to_string()),
+      "--link-arg" => link.args.push(value("--link-arg")?),
+      "--linker" => link.linker = value("--linker")?,
+      f if f.starts_with('-') => return Err(format!("Unknown option '{}'", f)),
+      _ if input.is_none() => input = Some(PathBuf::from(arg)),
+      _ => return Err(format!("Unexpected argument '{}'", arg)),
+    }
+  }
+
+  match backend.as_str() {
+    "cranelift" => {}
+    "llvm" if command != "run" => {}
+    "llvm" => return Err("The llvm backend can't run programs, use it with build".to_string()),
+    "c" if command != "run" => {}
+    "c" => return Err("The c backend can't run programs, use it with build".to_string()),
+    "interp" if command != "build" => {}
+    "interp" => return Err("The interp backend can't build files, use it with run".to_string()),
+    _ => return Err(format!("Unknown backend '{}'", backend)),
+  }
+  let wasm = target.as_deref().is_some_and(backend::is_wasm);
+  if wasm && backend != "cranelift" {
+    return Err("Only the cranelift backend targets wasm32".to_string());
+  }
+  if emit.contains(&Emit::Clif) && (backend != "cranelift" || wasm) {
+    return Err("Only the cranelift backend emits clif, and not for wasm32".to_string());
+  }
+  if emit.contains(&Emit::Wat) && !wasm {
+    return Err("Only wasm32 targets emit wat".to_string());
+  }
+  if emit.contains(&Emit::LlvmIr) && backend != "llvm" {
+    return Err("Only the llvm backend emits llvm-ir".to_string());
+  }
+  if emit.contains(&Emit::C) && backend != "c" {
+    return Err("Only the c backend emits c".to_string());
+  }
+  if emit.contains(&Emit::Obj) && backend == "c" {
+    return Err("The c backend doesn't emit object files, use --emit=c".to_string());
+  }
+
+  if emit.is_empty() {
+    emit.push(Emit::Exe);
+  }
+
+  Ok(Options {
+    command,
+    input: input.ok_or("Missing input file")?,
+    output,
+    emit,
+    target,
+    backend,
+    optimize,
+    link,
+  })
+}
+
+fn fail(msg: &str) -> ! {
+  eprintln!("error: {}", msg);
+  exit(2);
+}
+
+fn write_file(path: &Path, bytes: &[u8]) {
+  if let Err(e) = std::fs::write(path, bytes) {
+    fail(&format!("Couldn't write {}: {}", path.display(), e));
+  }
+}
+
+fn build(opts: &Options, session: &Session) -> Result<(), Diagnostics> {
+  if opts.emit.contains(&Emit::Tokens) {
+    let src = session.src();
+    for t in session.tokenize()? {
+      println!("{:?} {}..{} {:?}", t.kind, t.span.start, t.span.end, &src[t.span.start..t.span.end]);
+    }
+  }
+
+  // the AST is printed with the types analysis inferred, when it succeeds
+  let mut program = session.parse()?;
+  let analysis = session.analyse(&mut program);
+  if opts.emit.contains(&Emit::Ast) {
+    println!("{:#?}", program);
+  }
+  let program = session.lower(&program, &analysis?);
+
+  if opts.command == "check" {
+    return Ok(());
+  }
+  if opts.command == "run" {
+    let backend = match opts.backend.as_str() {
+      "cranelift" => "cranelift-jit",
+      backend => backend,
+    };
+    let value = session.evaluate(backend, &program)?;
+    println!("{}", value);
+    exit(value as i32);
+  }
+
+  let (obj, c, exe) = (opts.emit.contains(&Emit::Obj), opts.emit.contains(&Emit::C), opts.emit.contains(&Emit::Exe));
+  let ir = [Emit::Clif, Emit::LlvmIr, Emit::Wat].iter().any(|e| opts.emit.contains(e));
+  if !ir && !obj && !c && !exe {
+    return Ok(());
+  }
+
+  if opts.backend == "c" {
+    let source = session.transpile(&program)?;
+    return write_and_link(opts, source.as_bytes(), "c", c);
+  }
+
+  let artifacts = session.codegen(&program)?;
+  if ir {
+    println!("{}", artifacts.ir);
+  }
+  if opts.target.as_deref().is_some_and(backend::is_wasm) {
+    // the module is what's run, there's nothing to link
+    if obj || exe {
+      let path = opts.output.clone().unwrap_or_else(|| opts.input.with_extension("wasm"));
+      write_file(&path, &artifacts.object);
     }
+    return Ok(());
+  }
+  write_and_link(opts, &artifacts.object, "o", obj)
+}
+
+// Writes the object file or C source when it was asked for, and links it into
+// an executable when that was asked for.
+fn write_and_link(opts: &Options, contents: &[u8], extension: &str, write: bool) -> Result<(), Diagnostics> {
+  let exe = opts.emit.contains(&Emit::Exe);
+  // -o names the executable when there is one, the object file otherwise
+  let path = match &opts.output {
+    Some(path) if !exe => path.clone(),
+    _ => opts.input.with_extension(extension),
+  };
+  if write {
+    write_file(&path, contents);
+  }
+  if !exe {
+    return Ok(());
+  }
+
+  let exe_path = opts.output.clone()
+    .unwrap_or_else(|| opts.input.with_extension(std::env::consts::EXE_EXTENSION));
+  if write {
+    link::link(&[path], &exe_path, &opts.link)?;
+  } else if extension == "c" {
+    link::link_source(std::str::from_utf8(contents).unwrap(), &exe_path, &opts.link)?;
+  } else {
+    link::link_object(contents, &exe_path, &opts.link)?;
+  }
+  Ok(())
+}
+
+fn main() {
+  if std::env::args().nth(1).as_deref() == Some("repl") {
+    let mut repl = repl::Repl::new().unwrap_or_else(|err| fail(&err.msg));
+    return repl.run();
+  }
+
+  let opts = parse_args(std::env::args().skip(1).collect()).unwrap_or_else(|msg| {
+    eprintln!("error: {}\n\n{}", msg, USAGE);
+    exit(2);
+  });
+
+  let src = std::fs::read_to_string(&opts.input)
+    .unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", opts.input.display(), e)));
+
+  let mut session = Session::new(&src).optimize(opts.optimize);
+  if opts.command != "run" {
+    session = session.backend(&opts.backend);
+  }
+  if let Some(target) = &opts.target {
+    session = session.target(target);
+  }
+  if let Err(diagnostics) = build(&opts, &session) {
+    eprintln!("{}", diagnostics.render(&src));
+    exit(1);
   }
 }
@@ -0,0 +1,169 @@
+use std::io::{self, BufRead, Write};
+
+use cranelift_jit::JITModule;
+use scaffold::codegen::CodeGen;
+use scaffold::lang::{
+  analyse,
+  error::LangError,
+  hir,
+  parse::{self, ExprKind},
+  tokenize::{self, TokenKind},
+};
+use scaffold::Diagnostics;
+
+// Every input is analysed after the ones accepted before it, so it can use
+// their definitions and bindings, but only the input itself is compiled and
+// run, into a module that lives as long as the REPL.
+pub struct Repl {
+  history: String, // every input accepted so far
+  jit: CodeGen<JITModule>,
+}
+
+impl Repl {
+  pub fn new() -> Result<Self, LangError> {
+    Ok(Repl { history: String::new(), jit: CodeGen::jit()? })
+  }
+
+  pub fn run(&mut self) {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+      let mut input = String::new();
+      prompt("> ");
+      // keep reading until every def, if and while has been closed
+      loop {
+        match lines.next() {
+          Some(Ok(line)) => {
+            input.push_str(&line);
+            input.push('\n');
+          }
+          _ => return,
+        }
+        if open_blocks(&input) <= 0 {
+          break;
+        }
+        prompt(". ");
+      }
+
+      if input.trim().is_empty() {
+        continue;
+      }
+      match self.eval(&input) {
+        Ok(Some(value)) => println!("{}", value),
+        Ok(None) => {}
+        Err(report) => eprintln!("{}", report),
+      }
+    }
+  }
+
+  // Returns what to print for the input's value, nothing when it only
+  // defines things, or the rendered diagnostics. Errors point into the
+  // history, which ends with the input.
+  fn eval(&mut self, input: &str) -> Result<Option<String>, String> {
+    let src = format!("{}{}", self.history, input);
+    let render = |err: LangError| Diagnostics::from(err).render(&src);
+    let program = parse::parse(&src).map_err(render)?;
+    let settled = program.iter().take_while(|e| e.span.start < self.history.len()).count();
+    let analysis = analyse::analyse_after(&src, &program, settled).map_err(render)?;
+    let lowered = hir::lower_after(&src, &program, &analysis, settled);
+
+    // the input's functions and globals are in the module from here on, even
+    // if running it traps
+    let value = self.jit.eval(&lowered);
+    self.history = src.clone();
+    let value = value.map_err(render)?;
+
+    let stmts = &program[settled..];
+    if stmts.iter().all(|e| matches!(e.kind, ExprKind::FuncDef(..) | ExprKind::Let(..))) {
+      return Ok(None);
+    }
+    Ok(Some(match analysis.type_of(stmts.last().unwrap()) {
+      hir::Type::Bool => (value != 0).to_string(),
+      hir::Type::UInt64 => (value as u64).to_string(),
+      _ => value.to_string(),
+    }))
+  }
+}
+
+fn prompt(p: &str) {
+  print!("{}", p);
+  io::stdout().flush().unwrap();
+}
+
+// How many blocks are still waiting for their `end`.
+fn open_blocks(input: &str) -> i32 {
+  let toks = match tokenize::tokenize(input) {
+    Ok(toks) => toks,
+    Err(_) => return 0,
+  };
+  toks.iter().map(|t| match t.kind {
+    TokenKind::KeywordDef | TokenKind::KeywordIf | TokenKind::KeywordWhile => 1,
+    TokenKind::KeywordEnd => -1,
+    _ => 0,
+  }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Repl;
+
+  // What the REPL makes of each input in turn.
+  fn eval(inputs: &[&str]) -> Vec<Result<Option<String>, String>> {
+    let mut repl = Repl::new().unwrap();
+    inputs.iter().map(|input| repl.eval(input)).collect()
+  }
+
+  fn value(v: &str) -> Result<Option<String>, String> {
+    Ok(Some(v.to_string()))
+  }
+
+  #[test]
+  fn bindings_keep_their_values() {
+    assert_eq!(eval(&["var x = 1\n", "x = x + 4\n", "x\n", "let x = x * 2\n", "x\n"]), [
+      Ok(None), value("5"), value("5"), Ok(None), value("10"),
+    ]);
+  }
+
+  #[test]
+  fn functions_are_called_from_later_inputs() {
+    let double = "def twice(a Int32) -> Int32\n  a * 2\nend\n";
+    let quadruple = "def four_times(a Int32) -> Int32\n  twice(twice(a))\nend\n";
+    assert_eq!(eval(&[double, quadruple, "four_times(10) + twice(1)\n"]), [Ok(None), Ok(None), value("42")]);
+  }
+
+  #[test]
+  fn values_are_printed_as_their_type() {
+    assert_eq!(eval(&["1 == 1\n", "-1\n", "let u UInt64 = 18446744073709551615\n", "u\n", "not (u == 0)\n"]), [
+      value("true"), value("-1"), Ok(None), value("18446744073709551615"), value("true"),
+    ]);
+  }
+
+  #[test]
+  fn traps_are_reported_once() {
+    let def = "def f(a Int32) -> Int32\n  a / (a - 3)\nend\n";
+    assert_eq!(eval(&["let q = 10 / 0\n", def, "f(3)\n", "f(6) + 1\n"]), [
+      Err("1:9: error: Division by zero\n\tlet q = 10 / 0\n\t        ~~~~~~".to_string()),
+      Ok(None),
+      Err("3:3: error: Division by zero\n\t  a / (a - 3)\n\t  ~~~~~~~~~~~".to_string()),
+      value("3"),
+    ]);
+
+    assert_eq!(eval(&["let a Int32 = -2147483648\n", "a / -1\n", "a % -1\n", "a / 2\n"]), [
+      Ok(None),
+      Err("2:1: error: Division overflow\n\ta / -1\n\t~~~~~~".to_string()),
+      Err("3:1: error: Division overflow\n\ta % -1\n\t~~~~~~".to_string()),
+      value("-1073741824"),
+    ]);
+  }
+
+  #[test]
+  fn rejected_inputs_are_forgotten() {
+    assert_eq!(eval(&["let x = 1\n", "let y Int32 = x\n", "let x Int32 = 2\n", "x + y\n", "x\n"]), [
+      Ok(None),
+      Err("2:15: error: Expected Int32, got Int64\n\tlet y Int32 = x\n\t              ~".to_string()),
+      Ok(None),
+      Err("3:5: error: Undefined variable 'y'\n\tx + y\n\t    ~".to_string()),
+      value("2"),
+    ]);
+  }
+}
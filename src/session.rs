@@ -0,0 +1,173 @@
+use std::path::Path;
+
+use crate::backend::{self, Output};
+use crate::lang::{
+  self,
+  analyse::Analysis,
+  error::{lang_error_global, report_error, LangError, LangErrorKind},
+  hir::Program,
+  parse::Expr,
+  tokenize::Token,
+};
+use crate::link::{self, LinkOptions};
+
+// What codegen produced, kept in memory so embedders decide where it goes.
+#[derive(Debug, Clone)]
+pub struct Artifacts {
+  pub ir: String, // CLIF, LLVM IR from the llvm backend, or WAT for wasm32
+  pub object: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+  pub errors: Vec<LangError>,
+}
+
+impl Diagnostics {
+  // Renders every error against the source it was reported for, one per line.
+  pub fn render(&self, src: &str) -> String {
+    let mut lines = vec![];
+    for err in &self.errors {
+      lines.push(report_error(src, err.clone()));
+      if let LangErrorKind::Contextual(notes) = &err.kind {
+        lines.extend(notes.iter().map(|n| report_error(src, n.clone())));
+      }
+    }
+    lines.join("\n")
+  }
+}
+
+// Several errors reported together are kept apart, so each keeps its notes.
+impl From<LangError> for Diagnostics {
+  fn from(err: LangError) -> Self {
+    match err.kind {
+      LangErrorKind::Many(errors) => Diagnostics { errors },
+      _ => Diagnostics { errors: vec![err] },
+    }
+  }
+}
+
+impl From<Vec<LangError>> for Diagnostics {
+  fn from(errors: Vec<LangError>) -> Self {
+    Diagnostics { errors }
+  }
+}
+
+// Drives a single source file through the compiler's stages.
+pub struct Session {
+  src: String,
+  target: Option<String>,
+  backend: String,
+  optimize: bool,
+}
+
+impl Session {
+  pub fn new(src: &str) -> Self {
+    Session { src: src.to_string(), target: None, backend: "cranelift".to_string(), optimize: false }
+  }
+
+  // Generates code for `triple` rather than the host.
+  pub fn target(mut self, triple: &str) -> Self {
+    self.target = Some(triple.to_string());
+    self
+  }
+
+  // Picks the backend `codegen` emits object files with, cranelift by default.
+  pub fn backend(mut self, name: &str) -> Self {
+    self.backend = name.to_string();
+    self
+  }
+
+  pub fn optimize(mut self, optimize: bool) -> Self {
+    self.optimize = optimize;
+    self
+  }
+
+  pub fn src(&self) -> &str {
+    &self.src
+  }
+
+  pub fn tokenize(&self) -> Result<Vec<Token>, Diagnostics> {
+    Ok(lang::tokenize::tokenize(&self.src)?)
+  }
+
+  pub fn parse(&self) -> Result<Vec<Expr>, Diagnostics> {
+    Ok(lang::parse::parse(&self.src)?)
+  }
+
+  // Resolves and type checks the program, see `lang::analyse`, filling in
+  // the types it inferred for lets and functions that omit them.
+  pub fn analyse(&self, program: &mut [Expr]) -> Result<Analysis, Diagnostics> {
+    let analysis = lang::analyse::analyse(&self.src, program)?;
+    analysis.annotate(program);
+    Ok(analysis)
+  }
+
+  // Lowers an analysed program into the form backends consume, see `lang::hir`.
+  pub fn lower(&self, program: &[Expr], analysis: &Analysis) -> Program {
+    lang::hir::lower(&self.src, program, analysis)
+  }
+
+  // Hands the program to the backend named `backend`, see `backend::create`.
+  pub fn emit(&self, backend: &str, program: &Program) -> Result<Output, Diagnostics> {
+    let backend = backend::create(backend, self.target.as_deref(), self.optimize)?;
+    Ok(backend::drive(backend, program)?)
+  }
+
+  pub fn codegen(&self, program: &Program) -> Result<Artifacts, Diagnostics> {
+    match self.emit(&self.backend, program)? {
+      Output::Object { ir, object } => Ok(Artifacts { ir, object }),
+      _ => Err(lang_error_global(&format!("The {} backend can't emit object files", self.backend)).into()),
+    }
+  }
+
+  // Translates the program into a C translation unit.
+  pub fn transpile(&self, program: &Program) -> Result<String, Diagnostics> {
+    match self.emit("c", program)? {
+      Output::Source(source) => Ok(source),
+      _ => unreachable!("the c backend emits source"),
+    }
+  }
+
+  // JIT compiles the program for the host and calls its main.
+  pub fn execute(&self, program: &Program) -> Result<i64, Diagnostics> {
+    self.evaluate("cranelift-jit", program)
+  }
+
+  // Evaluates the program with the tree-walking interpreter instead.
+  pub fn interpret(&self, program: &Program) -> Result<i64, Diagnostics> {
+    self.evaluate("interp", program)
+  }
+
+  // Runs the program with a backend that runs programs, returning main's value.
+  pub fn evaluate(&self, backend: &str, program: &Program) -> Result<i64, Diagnostics> {
+    match self.emit(backend, program)? {
+      Output::Value(value) => Ok(value),
+      _ => Err(lang_error_global(&format!("The {} backend can't run programs", backend)).into()),
+    }
+  }
+
+  // Links the generated object into an executable at `output`.
+  pub fn link(&self, artifacts: &Artifacts, output: &Path, opts: &LinkOptions) -> Result<(), Diagnostics> {
+    Ok(link::link_object(&artifacts.object, output, opts)?)
+  }
+
+  // Parses, analyses and lowers the source without generating any code.
+  pub fn check(&self) -> Result<Program, Diagnostics> {
+    let mut program = self.parse()?;
+    let analysis = self.analyse(&mut program)?;
+    Ok(self.lower(&program, &analysis))
+  }
+
+  // Runs every stage, returning the generated IR and object file.
+  pub fn compile(&self) -> Result<Artifacts, Diagnostics> {
+    let program = self.check()?;
+    self.codegen(&program)
+  }
+
+  // Runs the program in-process, returning the value main returned.
+  pub fn run(&self) -> Result<i64, Diagnostics> {
+    let program = self.check()?;
+    self.execute(&program)
+  }
+}
@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A scratch directory per test, so tests running in parallel don't share files.
+struct Workdir(PathBuf);
+
+impl Workdir {
+  fn new(name: &str) -> Self {
+    let dir = std::env::temp_dir().join(format!("scaffold-cli-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    Workdir(dir)
+  }
+
+  fn write(&self, name: &str, src: &str) -> PathBuf {
+    let path = self.0.join(name);
+    std::fs::write(&path, src).unwrap();
+    path
+  }
+
+  fn scaffold(&self, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_scaffold")).current_dir(&self.0).args(args).output().unwrap()
+  }
+
+  fn path(&self, name: &str) -> PathBuf {
+    self.0.join(name)
+  }
+}
+
+impl Drop for Workdir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.0);
+  }
+}
+
+fn stdout(out: &Output) -> String {
+  String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+fn stderr(out: &Output) -> String {
+  String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+const TWICE: &str = "def twice(a Int32) -> Int32\n  a * 2\nend\ntwice(21)\n";
+
+#[test]
+fn check_reports_diagnostics_and_exits_with_1() {
+  let dir = Workdir::new("check");
+  dir.write("ok.sfd", TWICE);
+  dir.write("bad.sfd", "let x Bool = 1\ny\n");
+
+  let out = dir.scaffold(&["check", "ok.sfd"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  assert_eq!(stdout(&out), "");
+
+  let out = dir.scaffold(&["check", "bad.sfd"]);
+  assert_eq!(out.status.code(), Some(1));
+  assert_eq!(stderr(&out), "2:1: error: Undefined variable 'y'\n\ty\n\t~\n");
+}
+
+#[test]
+fn bad_arguments_exit_with_2() {
+  let dir = Workdir::new("usage");
+  dir.write("ok.sfd", TWICE);
+  for args in [&["compile", "ok.sfd"][..], &["build", "--frobnicate", "ok.sfd"], &["build"], &["check", "missing.sfd"],
+    &["run", "--backend=llvm", "ok.sfd"], &["run", "--backend=c", "ok.sfd"]] {
+    let out = dir.scaffold(args);
+    assert_eq!(out.status.code(), Some(2), "{:?}", args);
+    assert!(stderr(&out).starts_with("error: "), "{:?}: {}", args, stderr(&out));
+  }
+}
+
+#[test]
+fn build_links_an_executable() {
+  let dir = Workdir::new("build");
+  dir.write("twice.sfd", TWICE);
+
+  let out = dir.scaffold(&["build", "twice.sfd"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  let exe = dir.path("twice").with_extension(std::env::consts::EXE_EXTENSION);
+  assert_eq!(Command::new(&exe).status().unwrap().code(), Some(42));
+
+  let out = dir.scaffold(&["build", "-o", "named", "twice.sfd"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  assert_eq!(Command::new(dir.path("named")).status().unwrap().code(), Some(42));
+}
+
+#[test]
+fn emit_prints_and_writes_what_was_asked_for() {
+  let dir = Workdir::new("emit");
+  dir.write("twice.sfd", TWICE);
+
+  let out = dir.scaffold(&["build", "--emit=clif,obj", "-o", "twice.o", "twice.sfd"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  assert!(stdout(&out).starts_with("function u0:0(i32) -> i32"), "{}", stdout(&out));
+  assert_eq!(&std::fs::read(dir.path("twice.o")).unwrap()[..4], b"\x7fELF");
+  assert!(!dir.path("twice").exists());
+
+  let out = dir.scaffold(&["check", "--emit=tokens,ast", "twice.sfd"]);
+  assert!(out.status.success(), "{}", stderr(&out));
+  assert!(stdout(&out).starts_with("KeywordDef 0..3 \"def\"\n"), "{}", stdout(&out));
+  assert!(stdout(&out).contains("FuncDef("), "{}", stdout(&out));
+
+  let out = dir.scaffold(&["build", "--emit=wat", "twice.sfd"]);
+  assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn run_exits_with_mains_value() {
+  let dir = Workdir::new("run");
+  dir.write("twice.sfd", TWICE);
+  for backend in ["cranelift", "interp"] {
+    let out = dir.scaffold(&["run", &format!("--backend={}", backend), "twice.sfd"]);
+    assert_eq!(out.status.code(), Some(42), "{}: {}", backend, stderr(&out));
+    assert_eq!(stdout(&out), "42\n");
+  }
+}
+
+// Programs whose executables should do what the interpreter does: exit with
+// main's value, or report the same trap and abort.
+const PROGRAMS: [&str; 6] = [
+  "def sum(n UInt32) -> UInt32\n  var total UInt32 = 0\n  var i UInt32 = n\n  while i > 0\n    total = total + i\n    i = i - 1\n  end\n  total\nend\nsum(10) % 256\n",
+  // named like the C library functions the division's report is written with
+  "def write(a Int64) -> Int64\n  a\nend\ndef abort() -> Int64\n  0\nend\nwrite(1) / abort()\n",
+  "def f(a Int32, b Int32) -> Int32\n  a % b\nend\nf(1, 0)\n",
+  "def f(a Int32, b Int32) -> Int32\n  a / b\nend\nf(-2147483647 - 1, -1)\n",
+  "def f(a Int32, b Int32) -> Int32\n  a % b\nend\nf(-2147483647 - 1, -1)\n",
+  "let min = -9223372036854775807 - 1\nlet m = min / 2\n(m / -1) % 256\n",
+];
+
+fn assert_executables_behave_like_the_interpreter(backend: &str) {
+  use std::os::unix::process::ExitStatusExt;
+
+  let dir = Workdir::new(backend);
+  for (i, src) in PROGRAMS.iter().enumerate() {
+    let (name, file) = (format!("p{}", i), format!("p{}.sfd", i));
+    dir.write(&file, src);
+    let out = dir.scaffold(&["build", &format!("--backend={}", backend), &file]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let exe = Command::new(dir.path(&name)).output().unwrap();
+    let interp = dir.scaffold(&["run", "--backend=interp", &file]);
+    match interp.status.code() {
+      Some(1) => {
+        assert_eq!(exe.status.signal(), Some(6), "{}", src);
+        assert_eq!(stderr(&exe), stderr(&interp), "{}", src);
+      }
+      code => assert_eq!(exe.status.code(), code, "{}", src),
+    }
+  }
+}
+
+#[test]
+fn cranelift_executables_behave_like_the_interpreter() {
+  assert_executables_behave_like_the_interpreter("cranelift");
+}
+
+#[test]
+fn c_executables_behave_like_the_interpreter() {
+  assert_executables_behave_like_the_interpreter("c");
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn llvm_executables_behave_like_the_interpreter() {
+  assert_executables_behave_like_the_interpreter("llvm");
+}